@@ -0,0 +1,131 @@
+use crate::{EventSender, StateMachine, Transition, TryTransition};
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+
+/// The validated StateMachine together with its event sender.
+pub type Built<Event, State, UserData> = (
+    StateMachine<Event, State, UserData>,
+    EventSender<Event, State>,
+);
+
+/// The problem of the state graph found by [validate](StateMachine::validate).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BuildProblem<State> {
+    /// The Transition of the state was registered more than once, the last one replaced the others.
+    DuplicateTransition(State),
+    /// Neither the initial state nor its ancestors handle any event.
+    UnregisteredInitialState(State),
+    /// The state is reachable by the declared transitions, but neither it nor its ancestors handle any event
+    /// and it's not a final state, so the machine would get stuck in it.
+    UnregisteredState(State),
+}
+
+impl<State: Debug> Display for BuildProblem<State> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildProblem::DuplicateTransition(state) => {
+                write!(f, "duplicate transition of {state:?}")
+            }
+            BuildProblem::UnregisteredInitialState(state) => {
+                write!(f, "initial state {state:?} is not registered")
+            }
+            BuildProblem::UnregisteredState(state) => {
+                write!(f, "reachable state {state:?} is not registered")
+            }
+        }
+    }
+}
+
+/// The error returned by [build](StateMachineBuilder::build), listing all the problems of the state graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildError<State> {
+    pub problems: Vec<BuildProblem<State>>,
+}
+
+impl<State: Debug> Display for BuildError<State> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid state machine: ")?;
+        for (index, problem) in self.problems.iter().enumerate() {
+            if index > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<State: Debug> std::error::Error for BuildError<State> {}
+
+/// Builds the [StateMachine] validating its state graph.
+/// It's created by [builder](StateMachine::builder).
+///
+/// # Examples
+/// ```ignore
+/// let (stm, event_sender) = StateMachine::builder(100, State::Unknown, UserData::default())
+///     .transition(State::Unknown, Box::new(UnknownState {}))
+///     .transition(State::Charging, Box::new(ChargingState {}))
+///     .configure(|stm| {
+///         stm.on(State::Unknown, Event::PlugIn, State::Charging);
+///     })
+///     .build()?;
+/// ```
+pub struct StateMachineBuilder<Event, State, UserData> {
+    stm: StateMachine<Event, State, UserData>,
+    event_sender: EventSender<Event, State>,
+}
+
+impl<Event, State, UserData> StateMachineBuilder<Event, State, UserData>
+where
+    Event: Debug + Clone,
+    State: Debug + Eq + PartialEq + Copy + Clone + Hash,
+    UserData: Debug,
+{
+    pub(crate) fn new(size: usize, initial: State, user_data: UserData) -> Self {
+        let (stm, event_sender) = StateMachine::with_initial(size, initial, user_data);
+        Self { stm, event_sender }
+    }
+
+    /// Register the Transition of the state, see [add_transition](StateMachine::add_transition).
+    pub fn transition(
+        mut self,
+        state: State,
+        transition: Box<dyn Transition<Event, State, UserData> + Send + Sync>,
+    ) -> Self {
+        self.stm.add_transition(state, transition);
+        self
+    }
+
+    /// Register the fallible Transition of the state, see [add_try_transition](StateMachine::add_try_transition).
+    pub fn try_transition(
+        mut self,
+        state: State,
+        transition: Box<dyn TryTransition<Event, State, UserData> + Send + Sync>,
+    ) -> Self {
+        self.stm.add_try_transition(state, transition);
+        self
+    }
+
+    /// Add the orthogonal region, see [add_region](StateMachine::add_region).
+    pub fn region(mut self, initial: State) -> Self {
+        self.stm.add_region(initial);
+        self
+    }
+
+    /// Configure the rest of the machine, f.e. the transition table, the hierarchy or the timeouts.
+    /// * `configure` - the closure receiving the machine being built.
+    pub fn configure(
+        mut self,
+        configure: impl FnOnce(&mut StateMachine<Event, State, UserData>),
+    ) -> Self {
+        configure(&mut self.stm);
+        self
+    }
+
+    /// Validate the state graph, see [validate](StateMachine::validate).
+    /// * return the StateMachine and the event sender, or the [BuildError] listing all the problems.
+    pub fn build(self) -> Result<Built<Event, State, UserData>, BuildError<State>> {
+        self.stm.validate()?;
+        Ok((self.stm, self.event_sender))
+    }
+}
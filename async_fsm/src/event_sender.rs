@@ -0,0 +1,91 @@
+use crate::requester::Request;
+use std::fmt::Debug;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::{SendError, SendTimeoutError, TrySendError};
+use tokio::time::Instant;
+
+/// Sends the events to the StateMachine, it's created together with the machine and can be cloned to be used by many producers.
+/// The events sent by the event senders, the [requesters](crate::Requester) and the [scheduler](crate::Scheduler)
+/// share the same queue, so they are processed in the order of arrival.
+/// The machine keeps running as long as any event sender or requester is alive.
+pub struct EventSender<Event, State> {
+    sender: mpsc::Sender<Request<Event, State>>,
+}
+
+impl<Event, State> Clone for EventSender<Event, State> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<Event, State> Debug for EventSender<Event, State> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventSender")
+            .field("sender", &self.sender)
+            .finish()
+    }
+}
+
+impl<Event, State> EventSender<Event, State> {
+    pub(crate) fn new(sender: mpsc::Sender<Request<Event, State>>) -> Self {
+        Self { sender }
+    }
+
+    /// Send the event, waiting for the free space in the queue.
+    /// * `event` - the event to process.
+    /// * return the error holding the event if the machine has stopped.
+    pub async fn send(&self, event: Event) -> Result<(), SendError<Event>> {
+        self.sender
+            .send((event, Instant::now(), None))
+            .await
+            .map_err(|SendError((event, _, _))| SendError(event))
+    }
+
+    /// Send the event, waiting for the free space in the queue at most for the `timeout`.
+    /// * `event` - the event to process.
+    /// * `timeout` - the maximal time of the wait.
+    /// * return the error holding the event if the wait has timed out or the machine has stopped.
+    pub async fn send_timeout(
+        &self,
+        event: Event,
+        timeout: Duration,
+    ) -> Result<(), SendTimeoutError<Event>> {
+        self.sender
+            .send_timeout((event, Instant::now(), None), timeout)
+            .await
+            .map_err(|error| match error {
+                SendTimeoutError::Timeout((event, _, _)) => SendTimeoutError::Timeout(event),
+                SendTimeoutError::Closed((event, _, _)) => SendTimeoutError::Closed(event),
+            })
+    }
+
+    /// Send the event from the synchronous code, blocking the thread until there is the free space in the queue.
+    /// It panics when called within the asynchronous execution context, like [mpsc::Sender::blocking_send].
+    /// * `event` - the event to process.
+    /// * return the error holding the event if the machine has stopped.
+    pub fn blocking_send(&self, event: Event) -> Result<(), SendError<Event>> {
+        self.sender
+            .blocking_send((event, Instant::now(), None))
+            .map_err(|SendError((event, _, _))| SendError(event))
+    }
+
+    /// Send the event if there is the free space in the queue.
+    /// * `event` - the event to process.
+    /// * return the error holding the event if the queue is full or the machine has stopped.
+    pub fn try_send(&self, event: Event) -> Result<(), TrySendError<Event>> {
+        self.sender
+            .try_send((event, Instant::now(), None))
+            .map_err(|error| match error {
+                TrySendError::Full((event, _, _)) => TrySendError::Full(event),
+                TrySendError::Closed((event, _, _)) => TrySendError::Closed(event),
+            })
+    }
+
+    /// Check if the machine has stopped receiving the events.
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
@@ -1,44 +1,445 @@
 use async_trait::async_trait;
-use log::info;
-use std::collections::HashMap;
-use std::fmt::Debug;
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt::{Debug, Display};
 use std::hash::Hash;
+use std::mem::{discriminant, Discriminant};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::time::Duration;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender, WeakSender};
+use tokio::sync::watch;
 use tokio::time::Instant;
 
+mod builder;
+mod event_sender;
+mod panic;
+mod requester;
+mod scheduler;
+mod shutdown;
+mod state_handle;
+mod table;
+pub use builder::{BuildError, BuildProblem, Built, StateMachineBuilder};
+pub use event_sender::EventSender;
+use panic::{panic_message, CatchUnwind};
+use requester::{Request, Responder};
+pub use requester::{RequestError, Requester};
+pub use scheduler::{ScheduledEvent, Scheduler};
+pub use shutdown::{Shutdown, ShutdownHandle};
+pub use state_handle::{Snapshot, StateHandle, WaitError};
+use table::Matcher;
+pub use table::{
+    Action, Choice, ChoiceGuard, CompletionAction, CompletionGuard, CompletionRow, Guard,
+    TransitionRow,
+};
+
 /// The data catured on the incomming event.
+#[derive(Debug)]
 pub struct Data<Event, State, UserData> {
     /// Previous state - one of the states defined by the user.
     pub prev_state: Option<State>,
     /// Current state - one of the states defined by the user.
+    /// While the event is processed by the orthogonal region it's the state of that region.
     pub state: State,
+    /// Current states of all the orthogonal regions, the first one is the main region.
+    pub configuration: Vec<State>,
     /// UserData are defined and maintained by the user.
     /// Those data can be significant to store some useful informations
     /// that are shared across the different states.
     pub user_data: UserData,
-    /// Capture the time during handling incomming event.
-    #[allow(dead_code)]
-    pub events: HashMap<Event, Instant>,
+    /// The event being processed - the one which caused the entry into the current state.
+    /// It's None until the first event is received.
+    pub event: Option<Event>,
+    /// Capture the time the incomming event was received, the last time of each kind of the event.
+    /// The events are distinguished by their enum variant, not by the data they carry,
+    /// so it's bounded by the number of the event variants, see [last_seen](Data::last_seen).
+    pub events: HashMap<Discriminant<Event>, Instant>,
+    // the recent events with the time they were received, the oldest first
+    event_history: VecDeque<(Event, Instant)>,
+    // the maximal number of the events in the event history
+    event_history_size: usize,
+    // internal events raised while processing the event, with the time they were raised
+    raised: VecDeque<(Event, Instant)>,
+    // set by the Transition which handled the event, but stays in the current state
+    handled: bool,
+}
+
+/// The default number of the recent events kept in the [event history](Data::event_history).
+const EVENT_HISTORY_SIZE: usize = 16;
+
+impl<Event, State: Copy, UserData> Data<Event, State, UserData> {
+    fn new(configuration: Vec<State>, user_data: UserData) -> Self {
+        Self {
+            prev_state: None,
+            state: configuration[0],
+            configuration,
+            user_data,
+            event: None,
+            events: HashMap::new(),
+            event_history: VecDeque::new(),
+            event_history_size: EVENT_HISTORY_SIZE,
+            raised: VecDeque::new(),
+            handled: false,
+        }
+    }
+
+    /// Forget everything but the UserData and the event history size.
+    fn reset(&mut self, configuration: Vec<State>) {
+        self.prev_state = None;
+        self.state = configuration[0];
+        self.configuration = configuration;
+        self.event = None;
+        self.events.clear();
+        self.event_history.clear();
+        self.raised.clear();
+    }
+}
+
+impl<Event, State, UserData> Data<Event, State, UserData> {
+    /// Raise the internal event.
+    /// The internal events are processed right after the current event, in the order they were raised,
+    /// before the next event is received from the event channel.
+    /// * `event` - the internal event.
+    pub fn raise(&mut self, event: Event) {
+        self.raised.push_back((event, Instant::now()));
+    }
+
+    /// Mark the event as handled by the [Transition] returning the current state.
+    /// Otherwise returning the current state means the event is not handled - it's offered to the ancestors
+    /// and finally handled according to the [UnhandledPolicy].
+    pub fn stay(&mut self) {
+        self.handled = true;
+    }
+
+    /// The time the event of the same kind (enum variant) was received last time, no matter the data it carried.
+    /// It's useful for the guards, f.e. to check how long ago the event was received.
+    /// * `event` - the event of the looked up kind.
+    pub fn last_seen(&self, event: &Event) -> Option<Instant> {
+        self.last_seen_kind(discriminant(event))
+    }
+
+    /// The time the event of the kind was received last time, see [last_seen](Data::last_seen).
+    /// It doesn't need the event value, f.e. for the kind taken once with [discriminant].
+    /// * `kind` - the discriminant of the looked up event variant.
+    pub fn last_seen_kind(&self, kind: Discriminant<Event>) -> Option<Instant> {
+        self.events.get(&kind).copied()
+    }
+
+    /// The recent events with the time they were received, the oldest first.
+    /// The number of the kept events is set by [set_event_history_size](StateMachine::set_event_history_size).
+    pub fn event_history(&self) -> impl Iterator<Item = &(Event, Instant)> {
+        self.event_history.iter()
+    }
+
+    /// Record the event received at the given time.
+    fn record(&mut self, event: &Event, received_at: Instant)
+    where
+        Event: Clone,
+    {
+        self.events.insert(discriminant(event), received_at);
+        if self.event_history_size == 0 {
+            return;
+        }
+        if self.event_history.len() == self.event_history_size {
+            self.event_history.pop_front();
+        }
+        self.event_history.push_back((event.clone(), received_at));
+    }
 }
 
 /// The trains needs to be implemented for each "State" to ensure state transitions.
 #[async_trait]
 pub trait Transition<
-    Event: Debug + Copy + Clone + PartialEq + Eq + Hash,
-    State: Default + Debug + Eq + PartialEq + Copy + Clone + Hash,
-    UserData: Debug + Default,
+    Event: Debug + Clone,
+    State: Debug + Eq + PartialEq + Copy + Clone + Hash,
+    UserData: Debug,
 >
 {
     /// Process the incomming event and calculate next state.
     /// * `state` - the current state hold by a StateMachine.
-    /// * `data` - holds the state machine shared data f.e [prev_state](Data::prev_state) and the UserData defined by the user.
-    /// * return the next state.
-    async fn next(&mut self, event: Event, data: &Data<Event, State, UserData>) -> State;
+    /// * `data` - holds the state machine shared data f.e [prev_state](Data::prev_state) and the UserData defined by the user,
+    ///   which can be modified by the state.
+    /// * return the next state, the current one without calling [stay](Data::stay) means the event is not handled.
+    async fn next(&mut self, event: Event, data: &mut Data<Event, State, UserData>) -> State;
+
+    /// The method is called just after the switch to the new state.
+    /// * `data` - the state machine shared data, [event](Data::event) holds the event which caused the entry.
+    fn enter(&mut self, _data: &mut Data<Event, State, UserData>) {}
+
+    /// The method is called just before leaving the state, before the new state is entered.
+    /// * `event` - the event which triggered the transition, None for the [completion transition](StateMachine::on_completion)
+    ///   and the [shutdown](ShutdownHandle::shutdown).
+    /// * `next` - the state the machine is switching into, on the shutdown it's the state being left.
+    ///   For the transition into the [choice](StateMachine::add_choice) it's the choice pseudo-state,
+    ///   as the target is selected only after the states are left.
+    /// * `data` - the state machine shared data, [state](Data::state) still holds the state being left.
+    fn exit(
+        &mut self,
+        _event: Option<Event>,
+        _next: State,
+        _data: &mut Data<Event, State, UserData>,
+    ) {
+    }
+}
+
+/// The error returned by the [TryTransition].
+pub type TransitionError = Box<dyn Error + Send + Sync>;
+
+/// The fallible variant of the [Transition], f.e. for the states performing I/O.
+/// The returned error is handled according to the [ErrorPolicy] set by [set_error_policy](StateMachine::set_error_policy).
+#[async_trait]
+pub trait TryTransition<
+    Event: Debug + Clone,
+    State: Debug + Eq + PartialEq + Copy + Clone + Hash,
+    UserData: Debug,
+>
+{
+    /// Process the incomming event and calculate next state, see [next](Transition::next).
+    /// * return the next state or the error.
+    async fn try_next(
+        &mut self,
+        event: Event,
+        data: &mut Data<Event, State, UserData>,
+    ) -> Result<State, TransitionError>;
+
+    /// See [enter](Transition::enter).
+    fn enter(&mut self, _data: &mut Data<Event, State, UserData>) {}
+
+    /// See [exit](Transition::exit).
+    fn exit(
+        &mut self,
+        _event: Option<Event>,
+        _next: State,
+        _data: &mut Data<Event, State, UserData>,
+    ) {
+    }
+}
+
+/// The way the error returned by the [TryTransition] is handled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ErrorPolicy<State> {
+    /// Ignore the error, the event is considered as handled and the machine remains in the current state.
+    Stay,
+    /// Switch into the error state, as if the [TryTransition] returned it.
+    GoTo(State),
+    /// Stop the machine, the [process](StateMachine::process) returns the [Failure].
+    #[default]
+    Stop,
+}
+
+/// The way the panic raised while processing the event is handled, f.e. by the [Transition] or the transition action.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Restart the machine in its initial configuration with the fresh UserData: the active states are exited,
+    /// the [deferred](StateMachine::defer) events are dropped and the initial states are entered again.
+    /// The UserData is created by the [factory](StateMachine::set_user_data_factory), without it the UserData is kept.
+    Restart,
+    /// Return to the states which were active before the event which panicked: the states entered meanwhile are exited
+    /// and the states left meanwhile are entered again. For the internal or the recalled deferred event
+    /// it's the states active before that event, so the transitions already published are kept.
+    /// The internal events still queued are dropped.
+    ResetToPrevious,
+    /// Resume the panic, so it terminates the task running the [process](StateMachine::process).
+    #[default]
+    Propagate,
+}
+
+/// The way the event which didn't fire any transition in any of the active states is handled.
+/// The event is unhandled when there is neither the declared transition nor the [Transition] handling it,
+/// or the [Transition] returned the current state without calling [stay](Data::stay).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum UnhandledPolicy {
+    /// Drop the event silently.
+    #[default]
+    Ignore,
+    /// Log the event at the warn level.
+    Warn,
+    /// Log the event at the error level and report it as the error of the [TransitionRecord].
+    Error,
+    /// Send the event to the [dead letter subscribers](StateMachine::subscribe_dead_letters).
+    DeadLetter,
+}
+
+/// The event which wasn't handled, see [UnhandledPolicy::DeadLetter].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter<Event, State> {
+    /// The unhandled event.
+    pub event: Event,
+    /// The states of all the orthogonal regions the event was offered to.
+    pub configuration: Vec<State>,
+    /// The time the event was received - sent to the machine, [raised](Data::raise) or injected by the state timeout.
+    pub received_at: Instant,
+}
+
+/// The kind of the history pseudo-state.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum History {
+    /// Resume the last active direct substate, which is entered by default (its initial substates).
+    Shallow,
+    /// Resume the last active nested state with all its ancestors.
+    Deep,
+}
+
+/// The reason why the [process](StateMachine::process) has returned.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TerminationReason<State> {
+    /// Every orthogonal region reached a [final state](StateMachine::set_final), it holds the state of the main region.
+    Final(State),
+    /// All the event senders were dropped.
+    Closed,
+    /// The machine was stopped by the [ShutdownHandle].
+    Shutdown(Shutdown),
+    /// The [TryTransition] of the state failed and the machine was stopped, see [ErrorPolicy::Stop].
+    Failed(State),
+}
+
+/// The result of the [process](StateMachine::process) - why the machine has stopped and its final data.
+#[derive(Debug)]
+pub struct Termination<Event, State, UserData> {
+    pub reason: TerminationReason<State>,
+    pub data: Data<Event, State, UserData>,
+}
+
+/// The error returned by the [process](StateMachine::process) when the machine was stopped by the failed [TryTransition].
+#[derive(Debug)]
+pub struct Failure<Event, State, UserData> {
+    /// The error returned by the [TryTransition].
+    pub error: TransitionError,
+    /// The termination with the [Failed](TerminationReason::Failed) reason and the final data.
+    pub termination: Termination<Event, State, UserData>,
+}
+
+impl<Event, State: Debug, UserData> Display for Failure<Event, State, UserData> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.termination.reason, self.error)
+    }
+}
+
+impl<Event: Debug, State: Debug, UserData: Debug> Error for Failure<Event, State, UserData> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
 
-    // The method is called just after the switch to the new state.
-    fn enter(&mut self, _data: &Data<Event, State, UserData>) {}
+/// The record of the processed event, see [subscribe_transitions](StateMachine::subscribe_transitions).
+/// For the machine with orthogonal regions the states are the ones of the main region.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransitionRecord<Event, State> {
+    /// The number of the record, starting from 1.
+    pub sequence: u64,
+    /// The processed event.
+    pub event: Event,
+    /// The state before the event was processed.
+    pub prev_state: State,
+    /// The state after the event was processed.
+    pub state: State,
+    /// True if any transition fired, including the internal one which doesn't change the state.
+    /// False if the event was ignored or deferred.
+    pub fired: bool,
+    /// True if the event was [deferred](StateMachine::defer), its processing is published with another record
+    /// once it's no longer deferred, or with the error when it's dropped while still deferred.
+    /// The event deferred only by some of the orthogonal regions is published with both records: the deferred one,
+    /// followed by the one of the processing by the other regions.
+    pub deferred: bool,
+    /// The time the event was received - sent to the machine, [raised](Data::raise) or injected by the state timeout.
+    /// For the [deferred](StateMachine::defer) event it's the time it was received first.
+    pub received_at: Instant,
+    /// The time the processing of the event finished.
+    pub processed_at: Instant,
+    /// The error of the [TryTransition] which failed on the event, the [unhandled](UnhandledPolicy::Error) event
+    /// or the message of the panic raised while processing the event, see [PanicPolicy].
+    pub error: Option<String>,
+}
+
+/// The notification about the machine lifecycle, see [subscribe_notifications](StateMachine::subscribe_notifications).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notification<State> {
+    /// The [TryTransition] of the state failed, it's reported regardless of the [ErrorPolicy].
+    Failed { state: State, error: String },
+    /// The panic was raised in the state, it's reported regardless of the [PanicPolicy].
+    Panicked { state: State, message: String },
+    /// The [completion transitions](StateMachine::on_completion) fired on entering the state exceeded the limit,
+    /// the machine has returned to the state.
+    CompletionLooped(State),
+    /// The machine has stopped, no more state changes are published.
+    Terminated(TerminationReason<State>),
+}
+
+/// The maximal number of the completion transitions fired one after another.
+/// Reaching it means the completion transitions are most probably looped.
+const COMPLETION_LIMIT: usize = 100;
+
+/// The input of the event processor.
+enum Input<Event, State> {
+    Event(Event, Instant, Option<Responder<Event, State>>),
+    Shutdown(Shutdown),
+}
+
+/// The event being processed, kept to answer it when its processing panics.
+struct InFlight<Event, State> {
+    event: Event,
+    responder: Option<Responder<Event, State>>,
+    prev_state: State,
+    received_at: Instant,
+}
+
+/// The event deferred by some of the regions, it's still processed by the other ones.
+struct Deferred<Event, State> {
+    event: Event,
+    received_at: Instant,
+    // None when the request is already answered
+    responder: Option<Responder<Event, State>>,
+    // the regions which haven't processed the event yet
+    regions: Vec<usize>,
+}
+
+/// The declared transition which fired - (source state, index of the row).
+#[derive(Copy, Clone)]
+enum Fired<State> {
+    Row(State, usize),
+    Completion(State, usize),
+    // the next state returned by the Transition
+    Transition,
+}
+
+/// The handler registered for the state.
+enum Handler<Event, State, UserData> {
+    Infallible(Box<dyn Transition<Event, State, UserData> + Send + Sync>),
+    Fallible(Box<dyn TryTransition<Event, State, UserData> + Send + Sync>),
+}
+
+impl<Event, State, UserData> Handler<Event, State, UserData>
+where
+    Event: Debug + Clone,
+    State: Debug + Eq + PartialEq + Copy + Clone + Hash,
+    UserData: Debug,
+{
+    async fn next(
+        &mut self,
+        event: Event,
+        data: &mut Data<Event, State, UserData>,
+    ) -> Result<State, TransitionError> {
+        match self {
+            Handler::Infallible(transition) => Ok(transition.next(event, data).await),
+            Handler::Fallible(transition) => transition.try_next(event, data).await,
+        }
+    }
+
+    fn enter(&mut self, data: &mut Data<Event, State, UserData>) {
+        match self {
+            Handler::Infallible(transition) => transition.enter(data),
+            Handler::Fallible(transition) => transition.enter(data),
+        }
+    }
+
+    fn exit(&mut self, event: Option<Event>, next: State, data: &mut Data<Event, State, UserData>) {
+        match self {
+            Handler::Infallible(transition) => transition.exit(event, next, data),
+            Handler::Fallible(transition) => transition.exit(event, next, data),
+        }
+    }
 }
 
 /// Definition of the callback triggered during incomming event registration.
@@ -46,21 +447,89 @@ type FnOnEventRegister<Event, State, UserData> = fn(Event, &mut Data<Event, Stat
 
 /// StateMachine it is a Finite State Machine that provides an abstract interface and async interactions.
 pub struct StateMachine<Event, State, UserData> {
-    event_receiver: Receiver<Event>,
+    // the events of the event senders, the requesters and the scheduler in the order of arrival
+    event_receiver: Receiver<Request<Event, State>>,
+    // the sender is kept to create the requesters until the processing starts
+    request_sender: Option<Sender<Request<Event, State>>>,
+    // used by the scheduler, doesn't keep the event channel open
+    event_sender: WeakSender<Request<Event, State>>,
+    // the scheduled events are cancelled when it's dropped together with the machine
+    running: watch::Sender<()>,
     broadcast: (
         tokio::sync::broadcast::Sender<State>,
         tokio::sync::broadcast::Receiver<State>,
     ),
-    transitions: HashMap<State, Box<dyn Transition<Event, State, UserData> + Send + Sync>>,
+    configuration_broadcast: (
+        tokio::sync::broadcast::Sender<Vec<State>>,
+        tokio::sync::broadcast::Receiver<Vec<State>>,
+    ),
+    notifications: tokio::sync::broadcast::Sender<Notification<State>>,
+    transition_records: tokio::sync::broadcast::Sender<TransitionRecord<Event, State>>,
+    // the number of the processed events
+    sequence: u64,
+    snapshot: watch::Sender<Snapshot<State, UserData>>,
+    // set when any state handle exposes the UserData
+    clone_user_data: Option<fn(&UserData) -> UserData>,
+    // the sender is kept to create the shutdown handles
+    shutdown: (UnboundedSender<Shutdown>, UnboundedReceiver<Shutdown>),
+    transitions: HashMap<State, Handler<Event, State, UserData>>,
+    error_policy: ErrorPolicy<State>,
+    // the failed state and its error, which stopped the machine
+    failure: Option<(State, TransitionError)>,
+    // the error of the TryTransition which failed on the event being processed
+    event_error: Option<String>,
+    panic_policy: PanicPolicy,
+    // the states the machine is restarted in
+    initial_configuration: Vec<State>,
+    // the states the machine is reset to, taken before every dispatched event
+    reset_configuration: Vec<State>,
+    // source state, transitions in the declaration order
+    tables: HashMap<State, Vec<TransitionRow<Event, State, UserData>>>,
+    // child state, parent state
+    parents: HashMap<State, State>,
+    // composite state, substate entered by default
+    initial_substates: HashMap<State, State>,
+    // history pseudo-state, (composite state, kind of history)
+    histories: HashMap<State, (State, History)>,
+    // choice or junction pseudo-state, its branches
+    choices: HashMap<State, Choice<Event, State, UserData>>,
+    // composite state, its last active direct substate
+    last_substates: HashMap<State, State>,
+    // composite state, its last active nested state
+    last_nested_states: HashMap<State, State>,
+    // state, (timeout, event injected on the timeout)
+    timeouts: HashMap<State, (Duration, Event)>,
+    // active state, the deadline of its timeout
+    timers: HashMap<State, Instant>,
+    // states entered and not exited yet, in the order of the entry
+    entered: Vec<State>,
+    // source state, completion transitions in the declaration order
+    completions: HashMap<State, Vec<CompletionRow<Event, State, UserData>>>,
+    // states which terminate the machine
+    finals: HashSet<State>,
+    // state, matchers of the events deferred by the state
+    deferrals: HashMap<State, Vec<Matcher<Event>>>,
+    // deferred events in the order of arrival
+    deferred: VecDeque<Deferred<Event, State>>,
+    // the event being processed, None when it's already answered
+    in_flight: Option<InFlight<Event, State>>,
     data: Data<Event, State, UserData>,
     on_event_register: Option<FnOnEventRegister<Event, State, UserData>>,
+    // creates the fresh UserData on the restart
+    user_data_factory: Option<Box<dyn Fn() -> UserData + Send + Sync>>,
+    // states which Transition was registered more than once
+    duplicates: Vec<State>,
+    unhandled_policy: UnhandledPolicy,
+    // state, the policy overriding the machine one
+    state_unhandled_policies: HashMap<State, UnhandledPolicy>,
+    dead_letters: tokio::sync::broadcast::Sender<DeadLetter<Event, State>>,
 }
 
 impl<Event, State, UserData> StateMachine<Event, State, UserData>
 where
-    Event: Debug + Copy + Clone + PartialEq + Eq + Hash,
+    Event: Debug + Clone,
     State: Default + Debug + Eq + PartialEq + Copy + Clone + Hash,
-    UserData: Debug + Default,
+    UserData: Debug + Default + 'static,
 {
     /// Creates a StateMachine
     ///
@@ -90,7 +559,7 @@ where
     ///
     /// #[async_trait]
     /// impl Transition<Event, State, UserData> for UnknownState {
-    ///     async fn next(&mut self, event: Event, data: &Data<Event, State, UserData>) -> State {
+    ///     async fn next(&mut self, event: Event, data: &mut Data<Event, State, UserData>) -> State {
     ///         match event {
     ///             Event::MouseClick => State::SomeState, // Transit into the new state.
     ///             _ => data.state, // Remain in the same state.
@@ -101,7 +570,7 @@ where
     ///
     /// #[async_trait]
     /// impl Transition<Event, State, UserData> for SomeState {
-    ///     async fn next(&mut self, event: Event, _data: &Data<Event, State, UserData>) -> State {
+    ///     async fn next(&mut self, event: Event, _data: &mut Data<Event, State, UserData>) -> State {
     ///         match event {
     ///             _ => State::Unknown, // Go back to the unknown state.
     ///         }
@@ -114,38 +583,104 @@ where
     ///     stm.add_transition(State::Unknown, Box::new(UnknownState {}));
     ///     stm.add_transition(State::SomeState, Box::new(SomeState {}));
     ///
-    ///     let mut states = stm.subscribe();
+    ///     let mut records = stm.subscribe_transitions();
     ///
     ///     // Spawn the task to process the state machine events.
     ///     let task = tokio::spawn(async move {
-    ///         stm.process().await;
+    ///         let _ = stm.process().await;
     ///     });
     ///
     ///     // Send the external events into StateMachine
     ///     let _ = event_sender.send(Event::MouseClick).await;
-    ///     assert_eq!(states.recv().await.unwrap(), State::SomeState);
+    ///     assert_eq!(records.recv().await.unwrap().state, State::SomeState);
     ///
     ///     let _ = event_sender.send(Event::KeyPress('q')).await;
-    ///     assert_eq!(states.recv().await.unwrap(), State::Unknown);
+    ///     assert_eq!(records.recv().await.unwrap().state, State::Unknown);
     ///     task.abort();
     /// }
     /// ```
     ///
-    pub fn new(size: usize) -> (Self, Sender<Event>) {
-        let (event_sender, event_receiver) = mpsc::channel::<Event>(size);
+    pub fn new(size: usize) -> (Self, EventSender<Event, State>) {
+        let (mut fsm, event_sender) =
+            Self::with_initial(size, State::default(), UserData::default());
+        fsm.set_user_data_factory(UserData::default);
+        (fsm, event_sender)
+    }
+}
+
+impl<Event, State, UserData> StateMachine<Event, State, UserData>
+where
+    Event: Debug + Clone,
+    State: Debug + Eq + PartialEq + Copy + Clone + Hash,
+    UserData: Debug,
+{
+    /// Creates a StateMachine starting in the `initial` state with the `user_data`,
+    /// for the types without the meaningful default value, see [new](StateMachine::new).
+    /// * `size` - the capacity of the event channel and the subscriptions.
+    /// * `initial` - the state the main region starts in.
+    /// * `user_data` - the initial UserData, f.e. the configuration loaded at the startup.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let config = Config::load()?;
+    /// let (mut stm, event_sender) = StateMachine::with_initial(100, State::Idle, UserData { config });
+    /// ```
+    pub fn with_initial(
+        size: usize,
+        initial: State,
+        user_data: UserData,
+    ) -> (Self, EventSender<Event, State>) {
+        let (event_sender, event_receiver) = mpsc::channel::<Request<Event, State>>(size);
         let fsm = Self {
             event_receiver,
+            request_sender: Some(event_sender.clone()),
+            event_sender: event_sender.downgrade(),
+            running: watch::channel(()).0,
             broadcast: broadcast::channel::<State>(size),
+            configuration_broadcast: broadcast::channel::<Vec<State>>(size),
+            notifications: broadcast::channel::<Notification<State>>(size).0,
+            transition_records: broadcast::channel::<TransitionRecord<Event, State>>(size).0,
+            sequence: 0,
+            snapshot: watch::channel(Snapshot {
+                state: initial,
+                configuration: vec![initial],
+                active: vec![initial],
+                user_data: None,
+            })
+            .0,
+            clone_user_data: None,
+            shutdown: mpsc::unbounded_channel(),
             transitions: HashMap::new(),
-            data: Data {
-                prev_state: None,
-                state: State::default(),
-                user_data: UserData::default(),
-                events: HashMap::new(),
-            },
+            error_policy: ErrorPolicy::default(),
+            failure: None,
+            event_error: None,
+            panic_policy: PanicPolicy::default(),
+            initial_configuration: Vec::new(),
+            reset_configuration: Vec::new(),
+            tables: HashMap::new(),
+            completions: HashMap::new(),
+            parents: HashMap::new(),
+            initial_substates: HashMap::new(),
+            histories: HashMap::new(),
+            choices: HashMap::new(),
+            last_substates: HashMap::new(),
+            last_nested_states: HashMap::new(),
+            timeouts: HashMap::new(),
+            timers: HashMap::new(),
+            entered: Vec::new(),
+            finals: HashSet::new(),
+            deferrals: HashMap::new(),
+            deferred: VecDeque::new(),
+            in_flight: None,
+            data: Data::new(vec![initial], user_data),
             on_event_register: None,
+            user_data_factory: None,
+            duplicates: Vec::new(),
+            unhandled_policy: UnhandledPolicy::default(),
+            state_unhandled_policies: HashMap::new(),
+            dead_letters: broadcast::channel::<DeadLetter<Event, State>>(size).0,
         };
-        (fsm, event_sender)
+        (fsm, EventSender::new(event_sender))
     }
 
     /// Add the possible transitions between the states.
@@ -156,227 +691,3840 @@ where
         state: State,
         transition: Box<dyn Transition<Event, State, UserData> + Send + Sync>,
     ) {
-        self.transitions.insert(state, transition);
-    }
-
-    /// Subscribe to a state changes.
-    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<State> {
-        self.broadcast.0.subscribe()
+        self.insert_handler(state, Handler::Infallible(transition));
     }
 
-    /// The handler to manipulate or store user specyfic data.
-    /// * `callback` - the callback closure called when the event is receviced.
-    /// The callback supposed to be used if user want to store or manipulate some specyfic data which could be reused in other states.
-    ///
-    /// # Examples
-    /// ```ignore
-    /// #[derive(Debug, Default)]
-    /// struct UserData {
-    ///    event_counter: u64,
-    /// }
-    /// stm.add_on_register_callback(|_, data| {
-    ///     data.user_data.event_counter = data.user_data.event_counter + 1;
-    /// });
-    /// ```
-    pub fn add_on_register_callback(
+    /// Add the fallible transition of the state, see [add_transition](StateMachine::add_transition).
+    /// * `state` - one of the states defined by the user.
+    /// * `transition` - The Transition which implementes [TryTransition](TryTransition::try_next) trait.
+    pub fn add_try_transition(
         &mut self,
-        callback: FnOnEventRegister<Event, State, UserData>,
+        state: State,
+        transition: Box<dyn TryTransition<Event, State, UserData> + Send + Sync>,
     ) {
-        self.on_event_register = Some(callback);
+        self.insert_handler(state, Handler::Fallible(transition));
     }
 
-    ///The event processor. It's responsible listen on receive event channel process the event in the current state
-    /// and switch into the new state. The state changes are
-    pub async fn process(&mut self) {
-        self.on_state_change();
-        while let Some(event) = self.event_receiver.recv().await {
-            self.register_event(event);
-            self.process_event(event).await;
-            self.broadcast.0.send(self.data.state).unwrap();
+    fn insert_handler(&mut self, state: State, handler: Handler<Event, State, UserData>) {
+        if self.transitions.insert(state, handler).is_some() {
+            warn!("[fsm] Transition of {state:?} was replaced");
+            if !self.duplicates.contains(&state) {
+                self.duplicates.push(state);
+            }
         }
     }
 
-    async fn process_event(&mut self, event: Event) {
-        if let Some(transition) = self.transitions.get_mut(&self.data.state) {
-            self.data.prev_state = Some(self.data.state);
-            self.data.state = transition.next(event.clone(), &mut self.data).await;
-            if self.data.prev_state.unwrap() != self.data.state {
-                self.on_state_change();
+    /// Create the [StateMachineBuilder], which validates the state graph before the machine is created.
+    /// * `size` - the capacity of the event channel and the subscriptions.
+    /// * `initial` - the state the main region starts in.
+    /// * `user_data` - the initial UserData.
+    pub fn builder(
+        size: usize,
+        initial: State,
+        user_data: UserData,
+    ) -> StateMachineBuilder<Event, State, UserData> {
+        StateMachineBuilder::new(size, initial, user_data)
+    }
+
+    /// Check the state graph for the problems which otherwise show up only at runtime:
+    /// the Transition registered more than once, the initial state or the state reachable by the declared
+    /// transitions which doesn't handle any event (neither by the [Transition], the transition table
+    /// nor by its ancestors) and isn't final. The targets of the custom [Transition] are unknown,
+    /// so only the declared transitions, completions, choices and histories are followed,
+    /// as well as the error state of the [ErrorPolicy::GoTo] when any [TryTransition] is registered.
+    /// * return the [BuildError] listing all the problems.
+    pub fn validate(&self) -> Result<(), BuildError<State>> {
+        let mut problems: Vec<BuildProblem<State>> = self
+            .duplicates
+            .iter()
+            .map(|state| BuildProblem::DuplicateTransition(*state))
+            .collect();
+        let initial = self.initial_states();
+        for state in &initial {
+            if !self.is_registered(*state) {
+                problems.push(BuildProblem::UnregisteredInitialState(*state));
             }
         }
-        info!(
-            "[fsm] Processed event: {event:?}; {:?} => {:?}",
-            self.data.prev_state, self.data.state
-        );
-    }
 
-    fn register_event(&mut self, event: Event) {
-        self.data.events.insert(event, Instant::now());
-        if let Some(callback) = self.on_event_register {
-            (callback)(event, &mut self.data);
+        let mut visited = HashSet::new();
+        let mut pending = initial.clone();
+        let fallible = self
+            .transitions
+            .values()
+            .any(|handler| matches!(handler, Handler::Fallible(_)));
+        if let (ErrorPolicy::GoTo(target), true) = (self.error_policy, fallible) {
+            pending.push(target);
+        }
+        pending.reverse();
+        while let Some(state) = pending.pop() {
+            if !visited.insert(state) {
+                continue;
+            }
+            if let Some(choice) = self.choices.get(&state) {
+                pending.extend(choice.targets());
+                continue;
+            }
+            if let Some((composite, _)) = self.histories.get(&state) {
+                pending.push(*composite);
+                continue;
+            }
+            if let Some(substate) = self.initial_descent(state).last() {
+                pending.push(*substate);
+                continue;
+            }
+            if !self.is_registered(state) && !initial.contains(&state) {
+                problems.push(BuildProblem::UnregisteredState(state));
+            }
+            for ancestor in self.lineage(state) {
+                let rows = self.tables.get(&ancestor).into_iter().flatten();
+                let completions = self.completions.get(&ancestor).into_iter().flatten();
+                pending.extend(rows.map(|row| row.target));
+                pending.extend(completions.map(|row| row.target));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(BuildError { problems })
         }
     }
 
-    fn on_state_change(&mut self) {
-        if let Some(transition) = self.transitions.get_mut(&self.data.state) {
-            transition.enter(&mut self.data);
-        }
+    /// The initial state of every orthogonal region, the innermost of its initial substates.
+    fn initial_states(&self) -> Vec<State> {
+        self.data
+            .configuration
+            .iter()
+            .map(|state| {
+                self.initial_descent(*state)
+                    .last()
+                    .copied()
+                    .unwrap_or(*state)
+            })
+            .collect()
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use tokio::task::JoinHandle;
+    /// Check if the state or any of its ancestors handles the events, or the state is final.
+    fn is_registered(&self, state: State) -> bool {
+        self.finals.contains(&state)
+            || self.lineage(state).iter().any(|state| {
+                self.transitions.contains_key(state)
+                    || self.tables.contains_key(state)
+                    || self.completions.contains_key(state)
+            })
+    }
 
-    #[derive(Default, Debug, Eq, PartialEq, Copy, Clone, Hash)]
-    pub enum State {
-        #[default]
-        Idle,
-        State1,
-        State2,
+    /// Set the way the errors returned by the [TryTransition] are handled, it's [Stop](ErrorPolicy::Stop) by default.
+    /// Every error is reported to the subscribers as [Failed](Notification::Failed).
+    /// * `policy` - the [ErrorPolicy].
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy<State>) {
+        self.error_policy = policy;
     }
 
-    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
-    pub enum Event {
-        Event1,
-        Event2,
-        Event3,
+    /// Set the way the unhandled events are handled, it's [Ignore](UnhandledPolicy::Ignore) by default.
+    /// * `policy` - the [UnhandledPolicy].
+    pub fn set_unhandled_policy(&mut self, policy: UnhandledPolicy) {
+        self.unhandled_policy = policy;
     }
 
-    #[derive(Debug, Default)]
-    struct UserData {
-        event_counter: u64,
+    /// Set the way the events unhandled in the `state` are handled, it overrides the machine policy
+    /// for the state and its substates. In the orthogonal regions the policy of the first region which sets it applies.
+    /// * `state` - one of the states defined by the user.
+    /// * `policy` - the [UnhandledPolicy].
+    pub fn set_state_unhandled_policy(&mut self, state: State, policy: UnhandledPolicy) {
+        self.state_unhandled_policies.insert(state, policy);
     }
 
-    struct IdleState;
+    /// Declare the transition from the `state` into the `target` state triggered by the `event`.
+    /// It's an alternative to the [Transition] implementation for the states which just switch on the event.
+    /// The declared transitions are checked in the declaration order before the [Transition] registered
+    /// for the same state, so the custom [Transition] can still handle the remaining events.
+    /// The transition targeting its source state is an internal one - it doesn't exit nor enter the state nor its active substates.
+    /// * `state` - the source state.
+    /// * `event` - the event triggering the transition.
+    /// * `target` - the state the machine is switching into.
+    /// * return the [TransitionRow] to set the optional [guard](TransitionRow::guard) and [action](TransitionRow::action).
+    ///
+    /// # Examples
+    /// ```ignore
+    /// stm.on(State::Idle, Event::PlugIn, State::Charging);
+    /// stm.on(State::Charging, Event::PlugOut, State::Idle)
+    ///     .guard(|_, data| data.user_data.charged)
+    ///     .action(|_, data| data.user_data.cycles += 1);
+    /// ```
+    pub fn on(
+        &mut self,
+        state: State,
+        event: Event,
+        target: State,
+    ) -> &mut TransitionRow<Event, State, UserData>
+    where
+        Event: PartialEq + Send + Sync + 'static,
+    {
+        let rows = self.tables.entry(state).or_default();
+        rows.push(TransitionRow::new(
+            state,
+            format!("{event:?}"),
+            Box::new(move |incomming| *incomming == event),
+            target,
+        ));
+        rows.last_mut().unwrap()
+    }
 
-    #[async_trait]
-    impl Transition<Event, State, UserData> for IdleState {
-        async fn next(&mut self, event: Event, data: &Data<Event, State, UserData>) -> State {
-            match event {
-                Event::Event1 => State::State1,
-                _ => data.state,
-            }
-        }
+    /// Declare the transition from the `state` into the `target` state triggered by the events matching the `matcher`.
+    /// It's useful for the events carrying the data, see [on](StateMachine::on).
+    /// * `state` - the source state.
+    /// * `matcher` - the closure which returns true for the events triggering the transition.
+    /// * `target` - the state the machine is switching into.
+    pub fn on_match(
+        &mut self,
+        state: State,
+        matcher: impl Fn(&Event) -> bool + Send + Sync + 'static,
+        target: State,
+    ) -> &mut TransitionRow<Event, State, UserData> {
+        let rows = self.tables.entry(state).or_default();
+        rows.push(TransitionRow::new(
+            state,
+            "<matcher>".to_string(),
+            Box::new(matcher),
+            target,
+        ));
+        rows.last_mut().unwrap()
     }
 
-    struct State1State;
-    #[async_trait]
-    impl Transition<Event, State, UserData> for State1State {
-        async fn next(&mut self, event: Event, data: &Data<Event, State, UserData>) -> State {
-            match event {
-                Event::Event2 => State::State2,
-                _ => data.state,
-            }
-        }
+    /// Declare the completion transition from the `state` into the `target` state.
+    /// The completion transition doesn't wait for any event, it fires as soon as the `state` is entered
+    /// and its guard holds. If none of the guards holds the machine rests in the `state`.
+    /// The completion transitions are useful for the transient decision states.
+    /// The chain of the completion transitions fired one after another is limited to detect the infinite loops.
+    /// The looped chain is abandoned and the machine returns to the state the chain started from,
+    /// it's reported as [CompletionLooped](Notification::CompletionLooped) and as the error of the [TransitionRecord].
+    /// * `state` - the source state.
+    /// * `target` - the state the machine is switching into.
+    /// * return the [CompletionRow] to set the optional [guard](CompletionRow::guard) and [action](CompletionRow::action).
+    ///
+    /// # Examples
+    /// ```ignore
+    /// stm.on(State::Idle, Event::Submit, State::Validate);
+    /// stm.on_completion(State::Validate, State::Accepted)
+    ///     .guard(|data| data.user_data.is_valid);
+    /// stm.on_completion(State::Validate, State::Rejected);
+    /// ```
+    pub fn on_completion(
+        &mut self,
+        state: State,
+        target: State,
+    ) -> &mut CompletionRow<Event, State, UserData> {
+        let rows = self.completions.entry(state).or_default();
+        rows.push(CompletionRow::new(state, target));
+        rows.last_mut().unwrap()
     }
 
-    struct State2State;
-    #[async_trait]
-    impl Transition<Event, State, UserData> for State2State {
-        async fn next(&mut self, event: Event, data: &Data<Event, State, UserData>) -> State {
-            if data.user_data.event_counter > 5 {
-                // to many events
-                return State::Idle;
-            }
-            match event {
-                Event::Event3 => State::Idle,
-                _ => data.state,
-            }
-        }
+    /// Declare the parent (composite) state of the state.
+    /// The event which is not handled by the state is passed to its parent, then to the parent's parent and so on.
+    /// The event is considered as not handled when the [Transition](Transition::next) returns the current state.
+    /// * `state` - the substate.
+    /// * `parent` - the composite state containing the `state`.
+    pub fn set_parent(&mut self, state: State, parent: State) {
+        self.parents.insert(state, parent);
+        self.publish_initial_snapshot();
     }
 
-    async fn create_stm() -> (
-        JoinHandle<()>,
-        tokio::sync::mpsc::Sender<Event>,
-        tokio::sync::broadcast::Receiver<State>,
-    ) {
-        let (mut stm, event_sender) = StateMachine::<Event, State, UserData>::new(100);
+    /// Declare the substate entered when the transition targets the composite state.
+    /// Without the initial substate the machine rests in the composite state itself.
+    /// * `state` - the composite state.
+    /// * `substate` - one of the substates of the `state`.
+    pub fn set_initial_substate(&mut self, state: State, substate: State) {
+        self.initial_substates.insert(state, substate);
+        self.publish_initial_snapshot();
+    }
 
-        stm.add_transition(State::Idle, Box::new(IdleState {}));
-        stm.add_transition(State::State1, Box::new(State1State {}));
-        stm.add_transition(State::State2, Box::new(State2State {}));
+    /// Declare the history pseudo-state of the composite state.
+    /// The transition targeting the `pseudo_state` re-enters the `state` resuming the substate
+    /// which was active when the `state` was left. If the `state` was never left it's entered by default.
+    /// The `pseudo_state` is never the current state, so it doesn't need its own [Transition].
+    /// * `pseudo_state` - one of the states defined by the user, used as the transition target.
+    /// * `state` - the composite state.
+    /// * `history` - the [History] kind.
+    pub fn add_history(&mut self, pseudo_state: State, state: State, history: History) {
+        self.histories.insert(pseudo_state, (state, history));
+    }
 
-        stm.add_on_register_callback(|_, data| {
-            data.user_data.event_counter = data.user_data.event_counter + 1;
-        });
+    /// Declare the choice pseudo-state.
+    /// The transition targeting the `pseudo_state` switches into the target of the first branch with the holding guard.
+    /// The guards are evaluated after leaving the source state and executing the transition action,
+    /// so they see the data modified by the action. When none of the guards holds and there is no
+    /// [otherwise](Choice::otherwise) branch the machine returns to the source state.
+    /// The `pseudo_state` is never the current state, so it doesn't need its own [Transition].
+    /// The states are left before the target is known, so their [exit](Transition::exit) receives the `pseudo_state` as `next`
+    /// and the states left are the ones not shared with the `pseudo_state`. Set the [parent](StateMachine::set_parent)
+    /// of the `pseudo_state` to the composite state of its targets, otherwise the composite state is left and entered again -
+    /// its hooks are called, its timeout restarts and its history records the left substate.
+    /// * `pseudo_state` - one of the states defined by the user, used as the transition target.
+    /// * return the [Choice] to declare the branches.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// stm.set_parent(State::Validate, State::Form);
+    /// stm.on(State::Idle, Event::Submit, State::Validate);
+    /// stm.add_choice(State::Validate)
+    ///     .when(|data| data.user_data.is_valid, State::Accepted)
+    ///     .otherwise(State::Rejected);
+    /// ```
+    pub fn add_choice(&mut self, pseudo_state: State) -> &mut Choice<Event, State, UserData> {
+        self.choices
+            .entry(pseudo_state)
+            .insert_entry(Choice::new(true))
+            .into_mut()
+    }
 
-        let sub = stm.subscribe();
+    /// Declare the junction pseudo-state, see [add_choice](StateMachine::add_choice).
+    /// Unlike the choice the guards are evaluated before leaving the source state.
+    /// When none of the guards holds and there is no [otherwise](Choice::otherwise) branch the transition doesn't fire.
+    /// * `pseudo_state` - one of the states defined by the user, used as the transition target.
+    /// * return the [Choice] to declare the branches.
+    pub fn add_junction(&mut self, pseudo_state: State) -> &mut Choice<Event, State, UserData> {
+        self.choices
+            .entry(pseudo_state)
+            .insert_entry(Choice::new(false))
+            .into_mut()
+    }
 
-        let task = tokio::spawn(async move {
-            let _ = stm.process().await;
-        });
+    /// Declare the timeout of the state. When the state remains active for the `duration`
+    /// the `event` is processed as if it was received from the event channel.
+    /// The timer starts when the state is entered and it's cancelled when the state is left.
+    /// * `state` - one of the states defined by the user, it can be the composite state.
+    /// * `duration` - the time after which the state times out.
+    /// * `event` - the event injected on the timeout.
+    pub fn set_timeout(&mut self, state: State, duration: Duration, event: Event) {
+        self.timeouts.insert(state, (duration, event));
+    }
 
-        (task, event_sender, sub)
+    /// Mark the state as final. When every orthogonal region rests in a final state
+    /// the [process](StateMachine::process) returns and the subscribers are notified
+    /// with [Terminated](Notification::Terminated). The events still queued are not processed.
+    /// * `state` - one of the states defined by the user.
+    pub fn set_final(&mut self, state: State) {
+        self.finals.insert(state);
     }
 
-    #[tokio::test]
-    async fn given_idle_state_when_event1_occur_then_state_change_to_state1() {
-        let (task, sender, mut states) = create_stm().await;
+    /// Create the [Scheduler] delivering the events to the machine after the delay, at the given time or periodically.
+    pub fn scheduler(&self) -> Scheduler<Event, State>
+    where
+        Event: Send + 'static,
+        State: Send + 'static,
+    {
+        Scheduler::new(self.event_sender.clone(), self.running.subscribe())
+    }
 
-        // when
-        let _ = sender.send(Event::Event1).await;
+    /// Create the [Requester] sending the events and awaiting the outcome of their processing.
+    /// The events sent by the requesters and the [event senders](EventSender) share the same queue,
+    /// so they are processed in the order of arrival.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let requester = stm.requester();
+    /// tokio::spawn(async move { stm.process().await });
+    /// let record = requester.request(Event::PlugIn).await?;
+    /// assert_eq!(record.state, State::Charging);
+    /// ```
+    pub fn requester(&self) -> Requester<Event, State> {
+        // The sender is set until the process is started, which consumes the machine.
+        Requester::new(self.request_sender.clone().unwrap())
+    }
 
-        // then
-        assert_eq!(states.recv().await.unwrap(), State::State1);
+    /// Create the [ShutdownHandle] stopping the [process](StateMachine::process).
+    /// On the shutdown the active states are exited, from the innermost one, and the final data is returned.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let shutdown = stm.shutdown_handle();
+    /// let task = tokio::spawn(async move { stm.process().await });
+    /// let _ = event_sender.send(Event::PlugIn).await;
+    /// shutdown.shutdown(Shutdown::Drain);
+    /// let user_data = task.await.unwrap().data.user_data;
+    /// ```
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(self.shutdown.0.clone())
+    }
 
-        task.abort();
+    /// Defer the event while the state is active.
+    /// The deferred event is not processed, but it's kept in the queue until the machine enters a state
+    /// which doesn't defer it. Then the deferred events are processed in the order they arrived.
+    /// The event is deferred when any of the active states or their ancestors defers it.
+    /// With the [orthogonal regions](StateMachine::add_region) the event is deferred only for the regions
+    /// whose active states defer it, the other regions process it right away. The [Requester] is answered
+    /// once the event is processed by any region.
+    /// The queue of the deferred events is unbounded, so the state shouldn't defer the events it's flooded with.
+    /// The events still deferred when the machine stops are dropped: each one is published with the error,
+    /// answered to its [Requester] and handled according to the [UnhandledPolicy], f.e. sent to the dead letters.
+    /// * `state` - one of the states defined by the user, it can be the composite state.
+    /// * `event` - the deferred event.
+    pub fn defer(&mut self, state: State, event: Event)
+    where
+        Event: PartialEq + Send + Sync + 'static,
+    {
+        self.defer_match(state, move |incomming| *incomming == event);
     }
 
-    #[tokio::test]
+    /// Defer the events matching the `matcher` while the state is active, see [defer](StateMachine::defer).
+    /// * `state` - one of the states defined by the user, it can be the composite state.
+    /// * `matcher` - the closure which returns true for the deferred events.
+    pub fn defer_match(
+        &mut self,
+        state: State,
+        matcher: impl Fn(&Event) -> bool + Send + Sync + 'static,
+    ) {
+        self.deferrals
+            .entry(state)
+            .or_default()
+            .push(Box::new(matcher));
+    }
+
+    /// Set the factory creating the fresh UserData when the machine is restarted, see [Restart](PanicPolicy::Restart).
+    /// The machine created by [new](StateMachine::new) uses the UserData default value.
+    /// * `factory` - the closure creating the UserData, f.e. from the configuration loaded at the startup.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let config = Config::load()?;
+    /// stm.set_user_data_factory(move || UserData::new(config.clone()));
+    /// ```
+    pub fn set_user_data_factory(
+        &mut self,
+        factory: impl Fn() -> UserData + Send + Sync + 'static,
+    ) {
+        self.user_data_factory = Some(Box::new(factory));
+    }
+
+    /// Set the way the panics raised while processing the event are handled, it's [Propagate](PanicPolicy::Propagate) by default.
+    /// Every panic is reported to the subscribers as [Panicked](Notification::Panicked).
+    /// The panic raised while entering the initial configuration is propagated, on the restart it's only reported.
+    /// The panics of the hooks called by the [Restart](PanicPolicy::Restart) and [ResetToPrevious](PanicPolicy::ResetToPrevious)
+    /// are reported and the hooks are skipped.
+    /// The panic raised by the exit hook on the [shutdown](StateMachine::shutdown_handle) skips just that hook,
+    /// unless it's propagated.
+    /// * `policy` - the [PanicPolicy].
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        self.panic_policy = policy;
+    }
+
+    /// Set the number of the recent events kept in the [event history](Data::event_history), it's 16 by default.
+    /// * `size` - the maximal number of the events, 0 disables the event history.
+    pub fn set_event_history_size(&mut self, size: usize) {
+        self.data.event_history_size = size;
+        while self.data.event_history.len() > size {
+            self.data.event_history.pop_front();
+        }
+    }
+
+    /// Add the orthogonal region running in parallel to the main region.
+    /// Every incomming event is processed by each region, in the order the regions were added.
+    /// The regions are independent, so each state should belong to only one of them.
+    /// * `initial` - the state the region starts in.
+    pub fn add_region(&mut self, initial: State) {
+        self.data.configuration.push(initial);
+        self.publish_initial_snapshot();
+    }
+
+    /// Subscribe to a state changes.
+    /// The state is published after every received event, including the ignored and [deferred](StateMachine::defer) ones.
+    /// For the machine with orthogonal regions it's the state of the main region.
+    /// The subscription carries only the states, so when the machine stops its receivers just see the channel closed
+    /// ([RecvError::Closed](tokio::sync::broadcast::error::RecvError::Closed)). Use the
+    /// [records](StateMachine::subscribe_transitions) for the state changes instead, the termination is sent
+    /// as [Terminated](Notification::Terminated) to the [notification subscribers](StateMachine::subscribe_notifications).
+    #[deprecated(
+        note = "the termination is seen only as the closed channel, use subscribe_transitions and subscribe_notifications"
+    )]
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<State> {
+        self.broadcast.0.subscribe()
+    }
+
+    /// Subscribe to the configuration changes - the states of all the orthogonal regions.
+    /// Like for [subscribe](StateMachine::subscribe) the termination is seen only as the channel closed.
+    /// Use the [StateHandle] for the configuration instead, the termination is sent
+    /// as [Terminated](Notification::Terminated) to the [notification subscribers](StateMachine::subscribe_notifications).
+    #[deprecated(
+        note = "the termination is seen only as the closed channel, use state_handle and subscribe_notifications"
+    )]
+    pub fn subscribe_configuration(&self) -> tokio::sync::broadcast::Receiver<Vec<State>> {
+        self.configuration_broadcast.0.subscribe()
+    }
+
+    /// Subscribe to the records of the processed events.
+    /// Unlike [subscribe](StateMachine::subscribe) the record tells which event was processed
+    /// and whether it fired any transition.
+    pub fn subscribe_transitions(
+        &self,
+    ) -> tokio::sync::broadcast::Receiver<TransitionRecord<Event, State>> {
+        self.transition_records.subscribe()
+    }
+
+    /// Create the [StateHandle] exposing the current state of the machine.
+    /// Until the machine is started the handle exposes its initial states together with their initial substates
+    /// and ancestors. The completion transitions fired on entering them are reflected once the machine is started.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let states = stm.state_handle();
+    /// tokio::spawn(async move { stm.process().await });
+    /// event_sender.send(Event::PlugIn).await.unwrap();
+    /// states.wait_for_with_timeout(State::Charging, Duration::from_secs(1)).await?;
+    /// ```
+    pub fn state_handle(&self) -> StateHandle<State, UserData> {
+        self.publish_initial_snapshot();
+        StateHandle::new(self.snapshot.subscribe())
+    }
+
+    /// Create the [StateHandle] exposing the current state of the machine together with the copy of the UserData.
+    /// The UserData is cloned after every processed event, before the machine is started it's the initial UserData.
+    pub fn state_handle_with_user_data(&mut self) -> StateHandle<State, UserData>
+    where
+        UserData: Clone,
+    {
+        self.clone_user_data = Some(UserData::clone);
+        self.state_handle()
+    }
+
+    /// Subscribe to the unhandled events, they are sent only with the [DeadLetter](UnhandledPolicy::DeadLetter) policy.
+    pub fn subscribe_dead_letters(
+        &self,
+    ) -> tokio::sync::broadcast::Receiver<DeadLetter<Event, State>> {
+        self.dead_letters.subscribe()
+    }
+
+    /// Subscribe to the notifications about the machine lifecycle, f.e. its termination.
+    pub fn subscribe_notifications(&self) -> tokio::sync::broadcast::Receiver<Notification<State>> {
+        self.notifications.subscribe()
+    }
+
+    /// The handler to manipulate or store user specyfic data.
+    /// * `callback` - the callback closure called when the event is receviced.
+    ///
+    /// The callback supposed to be used if user want to store or manipulate some specyfic data which could be reused in other states.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// #[derive(Debug, Default)]
+    /// struct UserData {
+    ///    event_counter: u64,
+    /// }
+    /// stm.add_on_register_callback(|_, data| {
+    ///     data.user_data.event_counter += 1;
+    /// });
+    /// ```
+    pub fn add_on_register_callback(
+        &mut self,
+        callback: FnOnEventRegister<Event, State, UserData>,
+    ) {
+        self.on_event_register = Some(callback);
+    }
+
+    ///The event processor. It's responsible listen on receive event channel process the event in the current state
+    /// and switch into the new state. The state changes are
+    /// published to the subscribers.
+    /// It returns when the machine reaches the [final state](StateMachine::set_final), all the event senders and requesters are dropped
+    /// or the machine is [shut down](StateMachine::shutdown_handle).
+    /// * return the [Termination] with the reason and the final data,
+    ///   or the [Failure] when the machine was stopped by the failed [TryTransition].
+    pub async fn process(
+        mut self,
+    ) -> Result<Termination<Event, State, UserData>, Failure<Event, State, UserData>> {
+        let reason = self.run().await;
+        self.drop_deferred("the state machine has stopped");
+        info!("[fsm] Terminated: {reason:?}");
+        let _ = self.notifications.send(Notification::Terminated(reason));
+        let termination = Termination {
+            reason,
+            data: self.data,
+        };
+        match self.failure {
+            Some((_, error)) => Err(Failure { error, termination }),
+            None => Ok(termination),
+        }
+    }
+
+    async fn run(&mut self) -> TerminationReason<State> {
+        self.initial_configuration = self.data.configuration.clone();
+        // Only the event senders and the requesters keep the event channel open.
+        self.request_sender = None;
+        self.start().await;
+        loop {
+            if let Some(reason) = self.stop_reason() {
+                return reason;
+            }
+            match self.next_input().await {
+                Some(Input::Event(event, received_at, responder)) => {
+                    self.step(event, received_at, responder).await
+                }
+                Some(Input::Shutdown(mode)) => {
+                    self.shutdown(mode).await;
+                    return self
+                        .stop_reason()
+                        .unwrap_or(TerminationReason::Shutdown(mode));
+                }
+                None => return TerminationReason::Closed,
+            }
+        }
+    }
+
+    /// Enter the initial configuration, the panic is propagated.
+    async fn start(&mut self) {
+        if let Err(panic) = self.enter_initial().await {
+            resume_unwind(panic);
+        }
+    }
+
+    /// Enter the initial configuration, the panic is reported and returned.
+    async fn enter_initial(&mut self) -> std::thread::Result<()> {
+        let this = &mut *self;
+        let started = CatchUnwind(Box::pin(async move {
+            this.enter_initial_configuration();
+            this.process_raised().await;
+            this.recall_deferred().await;
+        }))
+        .await;
+        if let Err(panic) = &started {
+            self.report_panic(self.data.state, panic.as_ref());
+        }
+        self.publish_snapshot();
+        started
+    }
+
+    /// Process the event and the internal events it raised, the panic is handled according to the [PanicPolicy].
+    async fn step(
+        &mut self,
+        event: Event,
+        received_at: Instant,
+        responder: Option<Responder<Event, State>>,
+    ) {
+        self.reset_configuration = self.data.configuration.clone();
+        self.in_flight = Some(InFlight {
+            event: event.clone(),
+            responder,
+            prev_state: self.data.state,
+            received_at,
+        });
+        let this = &mut *self;
+        let processed = CatchUnwind(Box::pin(async move {
+            this.handle_event(event).await;
+        }))
+        .await;
+        let Err(panic) = processed else {
+            return;
+        };
+        let message = self.report_panic(self.data.state, panic.as_ref());
+        let in_flight = self.in_flight.take();
+        match self.panic_policy {
+            PanicPolicy::Propagate => resume_unwind(panic),
+            PanicPolicy::ResetToPrevious => {
+                self.data.raised.clear();
+                let configuration = std::mem::take(&mut self.reset_configuration);
+                self.restore_configuration(configuration);
+                self.publish_snapshot();
+            }
+            PanicPolicy::Restart => {
+                let initial = self.initial_configuration.clone();
+                self.exit_states(&[], |this, state| this.restored_state(state, &initial));
+                self.drop_deferred("the state machine has restarted");
+                if let Some(factory) = &self.user_data_factory {
+                    self.data.user_data = factory();
+                }
+                self.data.reset(initial);
+                self.entered.clear();
+                self.timers.clear();
+                self.last_substates.clear();
+                self.last_nested_states.clear();
+                // The panic is already reported, the entry is not retried.
+                let _ = self.enter_initial().await;
+            }
+        }
+        self.publish_panicked(in_flight, message);
+    }
+
+    /// Report the panic raised in the `state` to the notification subscribers, return its message.
+    fn report_panic(&self, state: State, panic: &(dyn std::any::Any + Send)) -> String {
+        let message = panic_message(panic);
+        error!("[fsm] Panicked in {state:?}: {message}");
+        let _ = self.notifications.send(Notification::Panicked {
+            state,
+            message: message.clone(),
+        });
+        message
+    }
+
+    /// Call the hook of the `state` catching its panic, which is reported and handled according to the [PanicPolicy]:
+    /// it's resumed for [Propagate](PanicPolicy::Propagate), otherwise the rest of the hook is skipped.
+    fn call_hook(&mut self, state: State, hook: impl FnOnce(&mut Self)) {
+        if let Err(panic) = catch_unwind(AssertUnwindSafe(|| hook(&mut *self))) {
+            self.report_panic(state, panic.as_ref());
+            if self.panic_policy == PanicPolicy::Propagate {
+                resume_unwind(panic);
+            }
+        }
+    }
+
+    /// Publish the state restored by the [PanicPolicy] and answer the event which panicked with the panic message.
+    fn publish_panicked(&mut self, in_flight: Option<InFlight<Event, State>>, message: String) {
+        self.broadcast.0.send(self.data.state).unwrap();
+        self.configuration_broadcast
+            .0
+            .send(self.data.configuration.clone())
+            .unwrap();
+        let Some(in_flight) = in_flight else {
+            return;
+        };
+        self.sequence += 1;
+        let record = TransitionRecord {
+            sequence: self.sequence,
+            event: in_flight.event,
+            prev_state: in_flight.prev_state,
+            state: self.data.state,
+            fired: false,
+            deferred: false,
+            received_at: in_flight.received_at,
+            processed_at: Instant::now(),
+            error: Some(message),
+        };
+        if let Some(responder) = in_flight.responder {
+            let _ = responder.send(record.clone());
+        }
+        let _ = self.transition_records.send(record);
+    }
+
+    /// Close the event channels, process the queued events in the drain mode and exit the active states.
+    async fn shutdown(&mut self, mode: Shutdown) {
+        info!("[fsm] Shutdown: {mode:?}");
+        self.event_receiver.close();
+        if mode == Shutdown::Drain {
+            while self.stop_reason().is_none() {
+                let Some((event, received_at, responder)) = self.event_receiver.recv().await else {
+                    break;
+                };
+                self.step(event, received_at, responder).await;
+            }
+        }
+        if self.stop_reason().is_none() {
+            self.exit_states(&[], |_, state| state);
+            self.data.state = self.data.configuration[0];
+        }
+    }
+
+    /// Exit the entered states but the `kept` ones, in the reverse order of the entry - from the innermost one.
+    /// The panic of the exit hook is handled according to the [PanicPolicy].
+    /// * `next` - the state passed to the exit hook of the left state.
+    fn exit_states(&mut self, kept: &[State], next: impl Fn(&Self, State) -> State) {
+        let entered = self.entered.clone();
+        for state in entered.iter().rev().copied() {
+            if kept.contains(&state) {
+                continue;
+            }
+            let next = next(self, state);
+            // the innermost entered state of the lineage, the current state of its region
+            self.data.state = entered
+                .iter()
+                .rev()
+                .copied()
+                .find(|entered| self.lineage(*entered).contains(&state))
+                .unwrap_or(state);
+            self.entered.retain(|entered| *entered != state);
+            self.timers.remove(&state);
+            self.call_hook(state, |this| {
+                if let Some(transition) = this.transitions.get_mut(&state) {
+                    transition.exit(None, next, &mut this.data);
+                }
+            });
+        }
+    }
+
+    /// Return to the `configuration` after the panic: exit the entered states which are not active in it
+    /// and enter its states which are not entered. The panics of the hooks are reported and the hooks skipped.
+    fn restore_configuration(&mut self, configuration: Vec<State>) {
+        let kept: Vec<State> = configuration
+            .iter()
+            .flat_map(|state| self.lineage(*state))
+            .collect();
+        self.exit_states(&kept, |this, state| {
+            this.restored_state(state, &configuration)
+        });
+        for restored in &configuration {
+            let mut lineage = self.lineage(*restored);
+            lineage.reverse();
+            self.data.state = *restored;
+            for state in lineage {
+                if !self.entered.contains(&state) {
+                    self.call_hook(state, |this| this.enter_state(state));
+                }
+            }
+        }
+        self.data.state = configuration[0];
+        self.data.configuration = configuration;
+    }
+
+    /// The state of the `configuration` sharing the outermost ancestor with the `state`,
+    /// the main region's one if there is no such state.
+    fn restored_state(&self, state: State, configuration: &[State]) -> State {
+        let outermost = self.lineage(state).last().copied();
+        configuration
+            .iter()
+            .copied()
+            .find(|restored| self.lineage(*restored).last().copied() == outermost)
+            .unwrap_or(configuration[0])
+    }
+
+    /// The reason to stop the machine - the failure or every orthogonal region resting in the final state.
+    fn stop_reason(&self) -> Option<TerminationReason<State>> {
+        if let Some((state, _)) = &self.failure {
+            return Some(TerminationReason::Failed(*state));
+        }
+        self.data
+            .configuration
+            .iter()
+            .all(|state| self.finals.contains(state))
+            .then_some(TerminationReason::Final(self.data.state))
+    }
+
+    /// Process the event and the internal events it raised, then recall the deferred events.
+    /// The responder of the event and the time it was received are taken from the [in-flight](InFlight) event.
+    async fn handle_event(&mut self, event: Event) {
+        let Some(in_flight) = self.in_flight.take() else {
+            return;
+        };
+        let dispatched = self
+            .dispatch_or_defer(event, in_flight.received_at, in_flight.responder)
+            .await;
+        if dispatched {
+            self.process_raised().await;
+            self.recall_deferred().await;
+        }
+    }
+
+    /// Dispatch the event to the regions which don't defer it and queue it for the ones which do.
+    /// The requester is answered by the dispatch, the deferred event keeps the responder only when no region processes it.
+    /// Return true if the event was dispatched to any region.
+    async fn dispatch_or_defer(
+        &mut self,
+        event: Event,
+        received_at: Instant,
+        responder: Option<Responder<Event, State>>,
+    ) -> bool {
+        let regions = (0..self.data.configuration.len()).collect();
+        let (deferring, dispatched) = self.split_deferring(&event, regions);
+        if dispatched.is_empty() {
+            self.defer_event(event, received_at, responder, deferring);
+            return false;
+        }
+        if !deferring.is_empty() {
+            self.defer_event(event.clone(), received_at, None, deferring);
+        }
+        self.dispatch(event, received_at, responder, &dispatched)
+            .await;
+        true
+    }
+
+    fn defer_event(
+        &mut self,
+        event: Event,
+        received_at: Instant,
+        responder: Option<Responder<Event, State>>,
+        regions: Vec<usize>,
+    ) {
+        info!("[fsm] Deferred event: {event:?} in regions {regions:?}");
+        self.publish_deferred(&event, received_at);
+        self.deferred.push_back(Deferred {
+            event,
+            received_at,
+            responder,
+            regions,
+        });
+    }
+
+    /// Process the internal events, including the ones raised meanwhile, the deferred ones are queued.
+    /// The remaining ones are dropped when the machine is stopped.
+    async fn process_raised(&mut self) {
+        while self.stop_reason().is_none() {
+            let Some((event, received_at)) = self.data.raised.pop_front() else {
+                return;
+            };
+            self.dispatch_or_defer(event, received_at, None).await;
+        }
+    }
+
+    /// Process the event by the `regions`, publish the record and answer the requester.
+    async fn dispatch(
+        &mut self,
+        event: Event,
+        received_at: Instant,
+        responder: Option<Responder<Event, State>>,
+        regions: &[usize],
+    ) {
+        let prev_state = self.data.state;
+        self.reset_configuration = self.data.configuration.clone();
+        self.in_flight = Some(InFlight {
+            event: event.clone(),
+            responder,
+            prev_state,
+            received_at,
+        });
+        self.event_error = None;
+        self.register_event(&event, received_at);
+        let fired = self.process_event(&event, regions).await;
+        if !fired && self.event_error.is_none() && self.failure.is_none() {
+            self.handle_unhandled(&event, received_at);
+        }
+        self.broadcast.0.send(self.data.state).unwrap();
+        self.configuration_broadcast
+            .0
+            .send(self.data.configuration.clone())
+            .unwrap();
+        self.publish_snapshot();
+        self.sequence += 1;
+        let record = TransitionRecord {
+            sequence: self.sequence,
+            event,
+            prev_state,
+            state: self.data.state,
+            fired,
+            deferred: false,
+            received_at,
+            processed_at: Instant::now(),
+            error: self.event_error.take(),
+        };
+        let responder = self
+            .in_flight
+            .take()
+            .and_then(|in_flight| in_flight.responder);
+        if let Some(responder) = responder {
+            let _ = responder.send(record.clone());
+        }
+        let _ = self.transition_records.send(record);
+    }
+
+    /// Publish the deferred event, the state doesn't change.
+    fn publish_deferred(&mut self, event: &Event, received_at: Instant) {
+        self.broadcast.0.send(self.data.state).unwrap();
+        self.configuration_broadcast
+            .0
+            .send(self.data.configuration.clone())
+            .unwrap();
+        self.sequence += 1;
+        let _ = self.transition_records.send(TransitionRecord {
+            sequence: self.sequence,
+            event: event.clone(),
+            prev_state: self.data.state,
+            state: self.data.state,
+            fired: false,
+            deferred: true,
+            received_at,
+            processed_at: Instant::now(),
+            error: None,
+        });
+    }
+
+    /// Drop the deferred events, each one is published with the error, answered to its requester
+    /// and handled according to the [UnhandledPolicy] of the active states.
+    /// * `reason` - why the events are dropped.
+    fn drop_deferred(&mut self, reason: &str) {
+        for Deferred {
+            event,
+            received_at,
+            responder,
+            ..
+        } in std::mem::take(&mut self.deferred)
+        {
+            warn!("[fsm] Dropped deferred event: {event:?}, {reason}");
+            self.handle_unhandled(&event, received_at);
+            // The record tells why the event was dropped, not that it was unhandled.
+            self.event_error = None;
+            self.sequence += 1;
+            let record = TransitionRecord {
+                sequence: self.sequence,
+                event,
+                prev_state: self.data.state,
+                state: self.data.state,
+                fired: false,
+                deferred: true,
+                received_at,
+                processed_at: Instant::now(),
+                error: Some(format!("deferred event dropped, {reason}")),
+            };
+            if let Some(responder) = responder {
+                let _ = responder.send(record.clone());
+            }
+            let _ = self.transition_records.send(record);
+        }
+    }
+
+    /// Apply the [UnhandledPolicy] of the active states to the event which didn't fire any transition.
+    fn handle_unhandled(&mut self, event: &Event, received_at: Instant) {
+        let policy = self
+            .data
+            .configuration
+            .iter()
+            .find_map(|state| {
+                self.lineage(*state)
+                    .iter()
+                    .find_map(|state| self.state_unhandled_policies.get(state).copied())
+            })
+            .unwrap_or(self.unhandled_policy);
+        let configuration = &self.data.configuration;
+        match policy {
+            UnhandledPolicy::Ignore => {}
+            UnhandledPolicy::Warn => warn!("[fsm] Unhandled event: {event:?} in {configuration:?}"),
+            UnhandledPolicy::Error => {
+                error!("[fsm] Unhandled event: {event:?} in {configuration:?}");
+                self.event_error = Some(format!("unhandled event {event:?} in {configuration:?}"));
+            }
+            UnhandledPolicy::DeadLetter => {
+                let _ = self.dead_letters.send(DeadLetter {
+                    event: event.clone(),
+                    configuration: configuration.clone(),
+                    received_at,
+                });
+            }
+        }
+    }
+
+    /// Publish the current state to the [StateHandle]s.
+    fn publish_snapshot(&self) {
+        self.send_snapshot(&self.data.configuration);
+    }
+
+    /// Publish the states the machine which isn't started yet is going to enter to the [StateHandle]s.
+    fn publish_initial_snapshot(&self) {
+        self.send_snapshot(&self.initial_states());
+    }
+
+    fn send_snapshot(&self, configuration: &[State]) {
+        let mut active = Vec::new();
+        for state in configuration {
+            for state in self.lineage(*state) {
+                if !active.contains(&state) {
+                    active.push(state);
+                }
+            }
+        }
+        self.snapshot.send_modify(|snapshot| {
+            snapshot.state = configuration[0];
+            snapshot.configuration = configuration.to_vec();
+            snapshot.active = active;
+            snapshot.user_data = self
+                .clone_user_data
+                .map(|clone_user_data| clone_user_data(&self.data.user_data));
+        });
+    }
+
+    /// Split the `regions` into the ones whose active state or its ancestors defer the event and the other ones.
+    fn split_deferring(&self, event: &Event, regions: Vec<usize>) -> (Vec<usize>, Vec<usize>) {
+        regions.into_iter().partition(|region| {
+            self.lineage(self.data.configuration[*region])
+                .iter()
+                .any(|state| {
+                    self.deferrals
+                        .get(state)
+                        .is_some_and(|matchers| matchers.iter().any(|matcher| matcher(event)))
+                })
+        })
+    }
+
+    /// Process the deferred events by the regions which no longer defer them, the oldest first.
+    async fn recall_deferred(&mut self) {
+        let mut index = 0;
+        while index < self.deferred.len() && self.stop_reason().is_none() {
+            let deferred = &self.deferred[index];
+            let (deferring, recalled) =
+                self.split_deferring(&deferred.event, deferred.regions.clone());
+            if recalled.is_empty() {
+                index += 1;
+                continue;
+            }
+            // The event stays queued for the regions which still defer it.
+            let (event, received_at, responder) = if deferring.is_empty() {
+                let deferred = self.deferred.remove(index).unwrap();
+                (deferred.event, deferred.received_at, deferred.responder)
+            } else {
+                let deferred = &mut self.deferred[index];
+                deferred.regions = deferring;
+                (
+                    deferred.event.clone(),
+                    deferred.received_at,
+                    deferred.responder.take(),
+                )
+            };
+            self.dispatch(event, received_at, responder, &recalled)
+                .await;
+            self.process_raised().await;
+            // The processed event could change the state, so check again from the oldest one.
+            index = 0;
+        }
+    }
+
+    /// Wait for the shutdown request, the event from the queue shared by the event senders and the requesters,
+    /// or the timeout of the active state, whichever comes first.
+    /// Return None when all the event senders and requesters are dropped.
+    async fn next_input(&mut self) -> Option<Input<Event, State>> {
+        let timer = self
+            .timers
+            .iter()
+            .min_by_key(|(_, deadline)| **deadline)
+            .map(|(state, deadline)| (*state, *deadline));
+        let deadline = timer.map_or_else(Instant::now, |(_, deadline)| deadline);
+        tokio::select! {
+            biased;
+            Some(mode) = self.shutdown.1.recv() => Some(Input::Shutdown(mode)),
+            _ = tokio::time::sleep_until(deadline), if timer.is_some() => {
+                let (state, _) = timer.unwrap();
+                self.timers.remove(&state);
+                info!("[fsm] State timed out: {state:?}");
+                self.timeouts.get(&state).map(|(_, event)| Input::Event(event.clone(), deadline, None))
+            }
+            request = self.event_receiver.recv() => {
+                request.map(|(event, received_at, responder)| Input::Event(event, received_at, responder))
+            }
+        }
+    }
+
+    /// Dispatch the event to the orthogonal `regions`, the main region's state is restored afterwards.
+    /// Return true if the transition fired in any region.
+    async fn process_event(&mut self, event: &Event, regions: &[usize]) -> bool {
+        let mut main_prev_state = self.data.prev_state;
+        let mut fired = false;
+        for region in regions.iter().copied() {
+            self.data.state = self.data.configuration[region];
+            fired |= self.process_region_event(event).await;
+            self.data.configuration[region] = self.data.state;
+            if region == 0 {
+                main_prev_state = self.data.prev_state;
+            }
+            if self.failure.is_some() {
+                break;
+            }
+        }
+        self.data.state = self.data.configuration[0];
+        self.data.prev_state = main_prev_state;
+        fired
+    }
+
+    /// Return true if the transition fired.
+    async fn process_region_event(&mut self, event: &Event) -> bool {
+        let source = self.data.state;
+        self.data.prev_state = Some(source);
+        // Offer the event to the current state first, then bubble it up to the ancestors.
+        let mut fired = None;
+        let mut handler = None;
+        for state in self.lineage(source) {
+            if let Some(index) = self.find_row(state, event) {
+                let row = &self.tables[&state][index];
+                handler = Some(row.to_string());
+                fired = Some((row.target, Fired::Row(state, index)));
+                break;
+            }
+            if let Some(transition) = self.transitions.get_mut(&state) {
+                self.data.handled = false;
+                match transition.next(event.clone(), &mut self.data).await {
+                    Ok(next) if next == source && self.data.handled => {
+                        handler = Some(format!("Transition of {state:?}"));
+                        fired = Some((next, Fired::Transition));
+                        break;
+                    }
+                    Ok(next) if next == source => {}
+                    Ok(next) => {
+                        handler = Some(format!("Transition of {state:?}"));
+                        fired = Some((next, Fired::Transition));
+                        break;
+                    }
+                    Err(error) => {
+                        handler = Some(format!("Failed transition of {state:?}"));
+                        fired = self.handle_error(state, event, error);
+                        break;
+                    }
+                }
+            }
+        }
+        let fired = match fired {
+            // the declared transition targeting the ancestor which declared it is an internal one as well
+            Some((target, fired @ Fired::Row(state, _))) if target == state => {
+                self.run_action(Some(event), fired);
+                true
+            }
+            Some((target, fired @ Fired::Transition)) if target == source => {
+                self.run_action(Some(event), fired);
+                true
+            }
+            Some((target, fired)) => {
+                let switched = self.switch_state(Some(event), target, fired);
+                if switched {
+                    self.complete_state();
+                }
+                switched
+            }
+            None => false,
+        };
+        info!(
+            "[fsm] Processed event: {event:?}; {:?} => {:?}; fired: {}",
+            self.data.prev_state,
+            self.data.state,
+            handler.filter(|_| fired).as_deref().unwrap_or("none")
+        );
+        fired
+    }
+
+    /// Report the error of the [TryTransition] and apply the [ErrorPolicy].
+    /// * return the transition fired instead of the failed one.
+    fn handle_error(
+        &mut self,
+        state: State,
+        event: &Event,
+        error: TransitionError,
+    ) -> Option<(State, Fired<State>)> {
+        error!("[fsm] Transition of {state:?} failed on {event:?}: {error}");
+        self.event_error = Some(error.to_string());
+        let _ = self.notifications.send(Notification::Failed {
+            state,
+            error: error.to_string(),
+        });
+        match self.error_policy {
+            ErrorPolicy::Stay => None,
+            ErrorPolicy::GoTo(target) => Some((target, Fired::Transition)),
+            ErrorPolicy::Stop => {
+                self.failure = Some((state, error));
+                None
+            }
+        }
+    }
+
+    /// The index of the first declared transition of the `state` fired by the event.
+    fn find_row(&self, state: State, event: &Event) -> Option<usize> {
+        self.tables
+            .get(&state)?
+            .iter()
+            .position(|row| row.fires(event, &self.data))
+    }
+
+    /// Execute the action of the declared transition.
+    fn run_action(&mut self, event: Option<&Event>, fired: Fired<State>) {
+        match (fired, event) {
+            (Fired::Row(state, index), Some(event)) => {
+                if let Some(action) = &self.tables[&state][index].action {
+                    action(event, &mut self.data);
+                }
+            }
+            (Fired::Completion(state, index), _) => {
+                if let Some(action) = &self.completions[&state][index].action {
+                    action(&mut self.data);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Fire the completion transitions of the current state, one after another as long as any guard holds.
+    /// When the limit is exceeded return to the current state and report the loop.
+    fn complete_state(&mut self) {
+        let start = self.data.state;
+        for _ in 0..COMPLETION_LIMIT {
+            let state = self.data.state;
+            let Some(index) = self
+                .completions
+                .get(&state)
+                .and_then(|rows| rows.iter().position(|row| row.fires(&self.data)))
+            else {
+                return;
+            };
+            let row = &self.completions[&state][index];
+            info!("[fsm] Completion transition: {row}");
+            if !self.switch_state(None, row.target, Fired::Completion(state, index)) {
+                return;
+            }
+        }
+        error!(
+            "[fsm] Completion transitions limit exceeded in {:?}, the transitions are looped, returning to {start:?}",
+            self.data.state
+        );
+        if self.data.state != start {
+            self.switch_state(None, start, Fired::Transition);
+        }
+        self.event_error = Some(format!(
+            "completion transitions looped, returned to {start:?}"
+        ));
+        let _ = self
+            .notifications
+            .send(Notification::CompletionLooped(start));
+    }
+
+    /// Exit the states from the current one up to the least common ancestor with the `target`,
+    /// execute the transition action and enter the states down to the `target` (and its initial substates).
+    /// Return false if the transition didn't fire, because none of the junction branches holds.
+    fn switch_state(&mut self, event: Option<&Event>, target: State, fired: Fired<State>) -> bool {
+        let Some(target) = self.resolve_target(target, false) else {
+            return false;
+        };
+        let source = self.data.state;
+        let source_lineage = self.lineage(source);
+        let target_lineage = self.lineage(target);
+        let exited: Vec<State> = source_lineage
+            .iter()
+            .copied()
+            .take_while(|state| !target_lineage.contains(state))
+            .collect();
+        let active = &source_lineage[exited.len()..];
+        // the choice is resolved after the action, until then the target is unknown
+        let choice = self
+            .choices
+            .get(&target)
+            .is_some_and(|choice| choice.dynamic);
+        let mut entered = if choice {
+            Vec::new()
+        } else {
+            self.entry_path(active, target)
+        };
+        // the choice target is unknown yet, so the exited states see the choice as the next one
+        let next = if choice {
+            target
+        } else {
+            entered.last().copied().unwrap_or(target)
+        };
+
+        for state in exited {
+            self.entered.retain(|entered| *entered != state);
+            if let Some(transition) = self.transitions.get_mut(&state) {
+                transition.exit(event.cloned(), next, &mut self.data);
+            }
+            self.timers.remove(&state);
+            if let Some(parent) = self.parents.get(&state) {
+                self.last_substates.insert(*parent, state);
+                self.last_nested_states.insert(*parent, self.data.state);
+            }
+        }
+        self.run_action(event, fired);
+        let mut target = target;
+        if choice {
+            target = self.resolve_target(target, true).unwrap_or(source);
+            entered = self.entry_path(active, target);
+        }
+        self.data.state = entered.last().copied().unwrap_or(target);
+        self.enter_states(&entered);
+        true
+    }
+
+    /// The states entered on the way from the `active` states to the `target`, from the outermost one.
+    fn entry_path(&self, active: &[State], target: State) -> Vec<State> {
+        let mut entered: Vec<State> = self
+            .lineage(target)
+            .into_iter()
+            .take_while(|state| !active.contains(state))
+            .collect();
+        entered.reverse();
+        entered.extend(self.initial_descent(target));
+        entered
+    }
+
+    /// Replace the history and junction pseudo-states with the states they select,
+    /// the choices are resolved only when `dynamic` is set.
+    /// Return None if none of the branches holds.
+    fn resolve_target(&self, target: State, dynamic: bool) -> Option<State> {
+        let mut target = target;
+        // Every pseudo-state is resolved at most once, unless they are looped.
+        for _ in 0..=self.histories.len() + self.choices.len() {
+            if let Some(choice) = self.choices.get(&target) {
+                if choice.dynamic && !dynamic {
+                    return Some(target);
+                }
+                let Some(selected) = choice.select(&self.data) else {
+                    error!("[fsm] None of the branches of {target:?} holds");
+                    return None;
+                };
+                target = selected;
+            } else if self.histories.contains_key(&target) {
+                target = self.resolve_history(target);
+            } else {
+                return Some(target);
+            }
+        }
+        error!("[fsm] Pseudo-states are looped at {target:?}");
+        None
+    }
+
+    /// Replace the history pseudo-state with the state it resumes.
+    fn resolve_history(&self, target: State) -> State {
+        match self.histories.get(&target) {
+            Some((state, History::Shallow)) => *self.last_substates.get(state).unwrap_or(state),
+            Some((state, History::Deep)) => *self.last_nested_states.get(state).unwrap_or(state),
+            None => target,
+        }
+    }
+
+    fn enter_initial_configuration(&mut self) {
+        for region in 0..self.data.configuration.len() {
+            let initial = self.data.configuration[region];
+            let mut entered = self.lineage(initial);
+            entered.reverse();
+            entered.extend(self.initial_descent(initial));
+            self.data.state = *entered.last().unwrap();
+            // recorded before the hooks, so the panic of the restarted machine leaves the region in the entered state
+            self.data.configuration[region] = self.data.state;
+            self.enter_states(&entered);
+            self.complete_state();
+            self.data.configuration[region] = self.data.state;
+        }
+        self.data.state = self.data.configuration[0];
+    }
+
+    fn enter_states(&mut self, states: &[State]) {
+        for state in states {
+            self.enter_state(*state);
+        }
+    }
+
+    /// Enter the state, it's considered entered even if its hook panics, so it's exited on the reset.
+    fn enter_state(&mut self, state: State) {
+        if !self.entered.contains(&state) {
+            self.entered.push(state);
+        }
+        if let Some((duration, _)) = self.timeouts.get(&state) {
+            self.timers.insert(state, Instant::now() + *duration);
+        }
+        if let Some(transition) = self.transitions.get_mut(&state) {
+            transition.enter(&mut self.data);
+        }
+    }
+
+    /// The state followed by its ancestors, from the innermost to the outermost one.
+    fn lineage(&self, state: State) -> Vec<State> {
+        let mut lineage = vec![state];
+        while let Some(parent) = self.parents.get(lineage.last().unwrap()) {
+            lineage.push(*parent);
+        }
+        lineage
+    }
+
+    /// The initial substates entered by default when the `state` is entered, from the outermost one.
+    fn initial_descent(&self, state: State) -> Vec<State> {
+        let mut descent = Vec::new();
+        let mut state = state;
+        while let Some(substate) = self.initial_substates.get(&state) {
+            descent.push(*substate);
+            state = *substate;
+        }
+        descent
+    }
+
+    fn register_event(&mut self, event: &Event, received_at: Instant) {
+        self.data.event = Some(event.clone());
+        self.data.record(event, received_at);
+        if let Some(callback) = self.on_event_register {
+            (callback)(event.clone(), &mut self.data);
+        }
+    }
+}
+
+#[cfg(test)]
+// the deprecated state subscriptions are still covered
+#[allow(deprecated)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc::error::SendTimeoutError;
+    use tokio::task::JoinHandle;
+
+    #[derive(Default, Debug, Eq, PartialEq, Copy, Clone, Hash)]
+    pub enum State {
+        #[default]
+        Idle,
+        State1,
+        State2,
+        // composite state of State1 and State2
+        Active,
+        // orthogonal region states, substates of State2 in the history tests
+        Offline,
+        Online,
+        // history pseudo-state of Active
+        ActiveHistory,
+        // choice or junction pseudo-state
+        Decision,
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+    pub enum Event {
+        Event1,
+        Event2,
+        Event3,
+    }
+
+    #[derive(Debug, Default)]
+    struct UserData {
+        event_counter: u64,
+    }
+
+    struct IdleState;
+
+    #[async_trait]
+    impl Transition<Event, State, UserData> for IdleState {
+        async fn next(&mut self, event: Event, data: &mut Data<Event, State, UserData>) -> State {
+            match event {
+                Event::Event1 => State::State1,
+                _ => data.state,
+            }
+        }
+    }
+
+    struct State1State;
+    #[async_trait]
+    impl Transition<Event, State, UserData> for State1State {
+        async fn next(&mut self, event: Event, data: &mut Data<Event, State, UserData>) -> State {
+            match event {
+                Event::Event2 => State::State2,
+                _ => data.state,
+            }
+        }
+    }
+
+    struct State2State;
+    #[async_trait]
+    impl Transition<Event, State, UserData> for State2State {
+        async fn next(&mut self, event: Event, data: &mut Data<Event, State, UserData>) -> State {
+            if data.user_data.event_counter > 5 {
+                // to many events
+                return State::Idle;
+            }
+            match event {
+                Event::Event3 => State::Idle,
+                _ => data.state,
+            }
+        }
+    }
+
+    async fn create_stm() -> (
+        JoinHandle<()>,
+        EventSender<Event, State>,
+        tokio::sync::broadcast::Receiver<State>,
+    ) {
+        let (mut stm, event_sender) = StateMachine::<Event, State, UserData>::new(100);
+
+        stm.add_transition(State::Idle, Box::new(IdleState {}));
+        stm.add_transition(State::State1, Box::new(State1State {}));
+        stm.add_transition(State::State2, Box::new(State2State {}));
+
+        stm.add_on_register_callback(|_, data| {
+            data.user_data.event_counter += 1;
+        });
+
+        let sub = stm.subscribe();
+
+        let task = tokio::spawn(async move {
+            let _ = stm.process().await;
+        });
+
+        (task, event_sender, sub)
+    }
+
+    #[tokio::test]
+    async fn given_idle_state_when_event1_occur_then_state_change_to_state1() {
+        let (task, sender, mut states) = create_stm().await;
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+
+        task.abort();
+    }
+
+    #[tokio::test]
     async fn given_idle_state_when_event2_occur_then_state_remain_the_same() {
         let (task, sender, mut states) = create_stm().await;
 
         // when
-        // Event is not handled in Idle state
+        // Event is not handled in Idle state
+        let _ = sender.send(Event::Event2).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_state1_when_event3_occur_then_state_return_to_idle() {
+        let (task, sender, mut states) = create_stm().await;
+
+        // given
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        let _ = sender.send(Event::Event2).await;
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+
+        //when
+        let _ = sender.send(Event::Event3).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_state2_when_events_counter_exceeded_then_state_return_to_idle() {
+        let (task, sender, mut states) = create_stm().await;
+
+        // given
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        let _ = sender.send(Event::Event2).await;
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+
+        //when
+        for _ in 0..3 {
+            let _ = sender.send(Event::Event1).await;
+            assert_eq!(states.recv().await.unwrap(), State::State2);
+        }
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        // after 5th event stae should get back to Idle.
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+
+        task.abort();
+    }
+
+    /// Transition recording its hooks calls into the shared trace.
+    struct TracedState {
+        name: State,
+        trace: Arc<Mutex<Vec<String>>>,
+        targets: Vec<(Event, State)>,
+    }
+
+    impl TracedState {
+        fn boxed(
+            name: State,
+            trace: &Arc<Mutex<Vec<String>>>,
+            targets: Vec<(Event, State)>,
+        ) -> Box<Self> {
+            Box::new(Self {
+                name,
+                trace: trace.clone(),
+                targets,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl Transition<Event, State, UserData> for TracedState {
+        async fn next(&mut self, event: Event, data: &mut Data<Event, State, UserData>) -> State {
+            self.trace
+                .lock()
+                .unwrap()
+                .push(format!("next {:?} {event:?}", self.name));
+            self.targets
+                .iter()
+                .find(|(ev, _)| *ev == event)
+                .map(|(_, target)| *target)
+                .unwrap_or(data.state)
+        }
+
+        fn enter(&mut self, _data: &mut Data<Event, State, UserData>) {
+            self.trace
+                .lock()
+                .unwrap()
+                .push(format!("enter {:?}", self.name));
+        }
+
+        fn exit(
+            &mut self,
+            event: Option<Event>,
+            next: State,
+            _data: &mut Data<Event, State, UserData>,
+        ) {
+            let event = event.map_or("completion".to_string(), |event| format!("{event:?}"));
+            self.trace
+                .lock()
+                .unwrap()
+                .push(format!("exit {:?} {event} {next:?}", self.name));
+        }
+    }
+
+    #[tokio::test]
+    async fn given_idle_state_when_transition_fires_then_exit_is_called_before_enter() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_transition(
+            State::Idle,
+            TracedState::boxed(State::Idle, &trace, vec![(Event::Event1, State::State1)]),
+        );
+        stm.add_transition(
+            State::State1,
+            TracedState::boxed(State::State1, &trace, vec![]),
+        );
+        let mut states = stm.subscribe();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        // Unhandled event does not leave the state.
+        let _ = sender.send(Event::Event2).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+
+        // then
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec![
+                "enter Idle",
+                "next Idle Event1",
+                "exit Idle Event1 State1",
+                "enter State1",
+                "next State1 Event2",
+            ]
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_nested_state_when_event_unhandled_then_parent_handles_it() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_transition(
+            State::Idle,
+            TracedState::boxed(State::Idle, &trace, vec![(Event::Event1, State::Active)]),
+        );
+        stm.add_transition(
+            State::Active,
+            TracedState::boxed(State::Active, &trace, vec![(Event::Event3, State::Idle)]),
+        );
+        stm.add_transition(
+            State::State1,
+            TracedState::boxed(State::State1, &trace, vec![(Event::Event2, State::State2)]),
+        );
+        stm.add_transition(
+            State::State2,
+            TracedState::boxed(State::State2, &trace, vec![]),
+        );
+        stm.set_parent(State::State1, State::Active);
+        stm.set_parent(State::State2, State::Active);
+        stm.set_initial_substate(State::Active, State::State1);
+        let mut states = stm.subscribe();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // given
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        let _ = sender.send(Event::Event2).await;
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+
+        // when
+        let _ = sender.send(Event::Event3).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec![
+                "enter Idle",
+                "next Idle Event1",
+                "exit Idle Event1 State1",
+                "enter Active",
+                "enter State1",
+                "next State1 Event2",
+                "exit State1 Event2 State2",
+                "enter State2",
+                "next State2 Event3",
+                "next Active Event3",
+                "exit State2 Event3 Idle",
+                "exit Active Event3 Idle",
+                "enter Idle",
+            ]
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_substate_active_when_parent_self_transition_fires_then_substate_is_kept() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        for state in [State::Active, State::State1, State::State2] {
+            stm.add_transition(state, TracedState::boxed(state, &trace, vec![]));
+        }
+        stm.set_parent(State::State1, State::Active);
+        stm.set_parent(State::State2, State::Active);
+        stm.set_initial_substate(State::Active, State::State1);
+        stm.on(State::Idle, Event::Event1, State::Active);
+        stm.on(State::State1, Event::Event2, State::State2);
+        let action_trace = trace.clone();
+        stm.on(State::Active, Event::Event3, State::Active)
+            .action(move |event, _| {
+                action_trace
+                    .lock()
+                    .unwrap()
+                    .push(format!("action {event:?}"));
+            });
+        let mut records = stm.subscribe_transitions();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // given
+        let _ = sender.send(Event::Event1).await;
+        let _ = sender.send(Event::Event2).await;
+        assert_eq!(records.recv().await.unwrap().state, State::State1);
+        assert_eq!(records.recv().await.unwrap().state, State::State2);
+
+        // when
+        let _ = sender.send(Event::Event3).await;
+
+        // then
+        let record = records.recv().await.unwrap();
+        assert!(record.fired);
+        assert_eq!(record.state, State::State2);
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec![
+                "enter Active",
+                "enter State1",
+                "exit State1 Event2 State2",
+                "enter State2",
+                "next State2 Event3",
+                "action Event3",
+            ]
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_two_regions_when_event_occur_then_each_region_processes_it() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_transition(
+            State::Idle,
+            TracedState::boxed(State::Idle, &trace, vec![(Event::Event1, State::State1)]),
+        );
+        stm.add_transition(
+            State::State1,
+            TracedState::boxed(State::State1, &trace, vec![(Event::Event2, State::State2)]),
+        );
+        stm.add_transition(
+            State::Offline,
+            TracedState::boxed(State::Offline, &trace, vec![(Event::Event1, State::Online)]),
+        );
+        stm.add_transition(
+            State::Online,
+            TracedState::boxed(State::Online, &trace, vec![(Event::Event3, State::Offline)]),
+        );
+        stm.add_region(State::Offline);
+        let mut states = stm.subscribe();
+        let mut configurations = stm.subscribe_configuration();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+        let _ = sender.send(Event::Event2).await;
+        let _ = sender.send(Event::Event3).await;
+
+        // then
+        assert_eq!(
+            configurations.recv().await.unwrap(),
+            vec![State::State1, State::Online]
+        );
+        assert_eq!(
+            configurations.recv().await.unwrap(),
+            vec![State::State2, State::Online]
+        );
+        assert_eq!(
+            configurations.recv().await.unwrap(),
+            vec![State::State2, State::Offline]
+        );
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+        assert_eq!(trace.lock().unwrap()[..2], ["enter Idle", "enter Offline"]);
+
+        task.abort();
+    }
+
+    async fn create_history_stm(
+        history: History,
+    ) -> (
+        JoinHandle<()>,
+        EventSender<Event, State>,
+        tokio::sync::broadcast::Receiver<State>,
+    ) {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let (mut stm, event_sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_transition(
+            State::Idle,
+            TracedState::boxed(
+                State::Idle,
+                &trace,
+                vec![(Event::Event1, State::ActiveHistory)],
+            ),
+        );
+        stm.add_transition(
+            State::Active,
+            TracedState::boxed(State::Active, &trace, vec![(Event::Event3, State::Idle)]),
+        );
+        stm.add_transition(
+            State::State1,
+            TracedState::boxed(State::State1, &trace, vec![(Event::Event2, State::State2)]),
+        );
+        stm.add_transition(
+            State::Offline,
+            TracedState::boxed(State::Offline, &trace, vec![(Event::Event1, State::Online)]),
+        );
+        stm.set_parent(State::State1, State::Active);
+        stm.set_parent(State::State2, State::Active);
+        stm.set_parent(State::Offline, State::State2);
+        stm.set_parent(State::Online, State::State2);
+        stm.set_initial_substate(State::Active, State::State1);
+        stm.set_initial_substate(State::State2, State::Offline);
+        stm.add_history(State::ActiveHistory, State::Active, history);
+
+        let mut sub = stm.subscribe();
+        let task = tokio::spawn(async move {
+            let _ = stm.process().await;
+        });
+
+        // Active was never left, so it's entered by default.
+        for (event, state) in [
+            (Event::Event1, State::State1),
+            (Event::Event2, State::Offline),
+            (Event::Event1, State::Online),
+            (Event::Event3, State::Idle),
+        ] {
+            let _ = event_sender.send(event).await;
+            assert_eq!(sub.recv().await.unwrap(), state);
+        }
+
+        (task, event_sender, sub)
+    }
+
+    #[tokio::test]
+    async fn given_shallow_history_when_composite_reentered_then_last_substate_is_resumed() {
+        let (task, sender, mut states) = create_history_stm(History::Shallow).await;
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        // State2 is resumed from its initial substate.
+        assert_eq!(states.recv().await.unwrap(), State::Offline);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_deep_history_when_composite_reentered_then_last_nested_state_is_resumed() {
+        let (task, sender, mut states) = create_history_stm(History::Deep).await;
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::Online);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_transition_table_when_row_fires_then_action_is_called_between_exit_and_enter() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        // The declared transition takes precedence over the Transition registered for the state.
+        stm.add_transition(
+            State::Idle,
+            TracedState::boxed(State::Idle, &trace, vec![(Event::Event1, State::State2)]),
+        );
+        stm.add_transition(
+            State::State1,
+            TracedState::boxed(State::State1, &trace, vec![(Event::Event2, State::State2)]),
+        );
+        let action_trace = trace.clone();
+        stm.on(State::Idle, Event::Event1, State::State1)
+            .action(move |event, _| {
+                action_trace
+                    .lock()
+                    .unwrap()
+                    .push(format!("action {event:?}"));
+            });
+        let mut states = stm.subscribe();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        // Not declared event is passed to the registered Transition.
+        let _ = sender.send(Event::Event2).await;
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+
+        // then
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec![
+                "enter Idle",
+                "exit Idle Event1 State1",
+                "action Event1",
+                "enter State1",
+                "next State1 Event2",
+                "exit State1 Event2 State2",
+            ]
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_guarded_rows_when_event_occur_then_first_row_with_holding_guard_fires() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_on_register_callback(|_, data| {
+            data.user_data.event_counter += 1;
+        });
+        stm.on(State::Idle, Event::Event1, State::State2)
+            .guard(|_, data| data.user_data.event_counter > 2);
+        stm.on(State::Idle, Event::Event1, State::State1)
+            .guard(|_, data| data.user_data.event_counter > 1)
+            .action(|_, data| data.user_data.event_counter += 10);
+        stm.on_match(State::State1, |_| true, State::Idle);
+        let mut states = stm.subscribe();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // given
+        // Event counter is 1, none of the guards holds.
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        let _ = sender.send(Event::Event3).await;
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+        // The action increased the event counter.
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+
+        task.abort();
+    }
+
+    #[test]
+    fn given_transition_row_when_displayed_then_it_describes_the_transition() {
+        let (mut stm, _sender) = StateMachine::<Event, State, UserData>::new(100);
+
+        let row = stm
+            .on(State::State2, Event::Event1, State::Idle)
+            .guard_named("too many events", |_, data| {
+                data.user_data.event_counter > 5
+            });
+        assert_eq!(row.to_string(), "State2 --Event1 [too many events]--> Idle");
+
+        let row = stm.on_match(State::State2, |_| true, State::Idle);
+        assert_eq!(row.to_string(), "State2 --<matcher>--> Idle");
+    }
+
+    /// Counts the Event2 retries and gives up after the third one.
+    struct RetryState;
+
+    #[async_trait]
+    impl Transition<Event, State, UserData> for RetryState {
+        async fn next(&mut self, event: Event, data: &mut Data<Event, State, UserData>) -> State {
+            match event {
+                Event::Event2 => {
+                    data.user_data.event_counter += 1;
+                    if data.user_data.event_counter == 3 {
+                        State::Idle
+                    } else {
+                        data.state
+                    }
+                }
+                _ => data.state,
+            }
+        }
+
+        fn enter(&mut self, data: &mut Data<Event, State, UserData>) {
+            if data.event == Some(Event::Event1) {
+                data.user_data.event_counter = 0;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn given_retry_state_when_retries_exceeded_then_state_return_to_idle() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.add_transition(State::State1, Box::new(RetryState));
+        let mut states = stm.subscribe();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // given
+        for (event, state) in [
+            (Event::Event1, State::State1),
+            (Event::Event2, State::State1),
+            (Event::Event2, State::State1),
+            (Event::Event2, State::Idle),
+            (Event::Event1, State::State1),
+        ] {
+            let _ = sender.send(event).await;
+            assert_eq!(states.recv().await.unwrap(), state);
+        }
+
+        // when
+        // The retries were reset on the entry.
+        let _ = sender.send(Event::Event2).await;
+        let _ = sender.send(Event::Event2).await;
+        let _ = sender.send(Event::Event2).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+
+        task.abort();
+    }
+
+    async fn create_timeout_stm() -> (
+        JoinHandle<()>,
+        EventSender<Event, State>,
+        tokio::sync::broadcast::Receiver<State>,
+    ) {
+        let (mut stm, event_sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.on(State::State1, Event::Event2, State::State2);
+        stm.on(State::State1, Event::Event3, State::Idle);
+        stm.on(State::State2, Event::Event3, State::Idle);
+        stm.set_timeout(State::State1, Duration::from_secs(10), Event::Event3);
+        let sub = stm.subscribe();
+        let task = tokio::spawn(async move {
+            let _ = stm.process().await;
+        });
+        (task, event_sender, sub)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_state_timeout_when_state_not_left_then_timeout_event_is_processed() {
+        let (task, sender, mut states) = create_timeout_stm().await;
+
+        // given
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        let entered = Instant::now();
+
+        // when
+        let state = states.recv().await.unwrap();
+
+        // then
+        assert_eq!(state, State::Idle);
+        assert_eq!(entered.elapsed(), Duration::from_secs(10));
+
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_state_timeout_when_state_left_earlier_then_timer_is_cancelled() {
+        let (task, sender, mut states) = create_timeout_stm().await;
+
+        // given
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        // when
+        let _ = sender.send(Event::Event2).await;
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+        tokio::time::sleep(Duration::from_secs(20)).await;
+
+        // then
+        assert!(states.try_recv().is_err());
+        let _ = sender.send(Event::Event3).await;
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_scheduled_event_when_delay_elapsed_then_event_is_processed() {
+        let (mut stm, _sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        let scheduler = stm.scheduler();
+        let mut states = stm.subscribe();
+        let task = tokio::spawn(async move { stm.process().await });
+        let scheduled = Instant::now();
+
+        // when
+        let _ = scheduler.send_after(Event::Event1, Duration::from_secs(5));
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        assert_eq!(scheduled.elapsed(), Duration::from_secs(5));
+
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_periodic_event_when_cancelled_then_next_deliveries_are_skipped() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.on(State::State1, Event::Event1, State::Idle);
+        stm.on(State::State1, Event::Event2, State::State2);
+        let scheduler = stm.scheduler();
+        let mut states = stm.subscribe();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // given
+        let periodic = scheduler.send_every(Event::Event1, Duration::from_secs(1));
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+
+        // when
+        periodic.cancel();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        // then
+        assert!(states.try_recv().is_err());
+        let _ = sender.send(Event::Event2).await;
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_scheduled_event_when_machine_dropped_then_delivery_is_cancelled() {
+        let (stm, _sender) = StateMachine::<Event, State, UserData>::new(100);
+        let scheduler = stm.scheduler();
+        let scheduled = scheduler.send_after(Event::Event1, Duration::from_secs(5));
+        let now = Instant::now();
+
+        // when
+        drop(stm);
+        tokio::task::yield_now().await;
+
+        // then
+        assert!(scheduled.is_finished());
+        assert_eq!(now.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn given_deferred_event_when_state_left_then_event_is_processed_in_new_state() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.on(State::Idle, Event::Event2, State::State2);
+        stm.on(State::State1, Event::Event3, State::Idle);
+        stm.defer(State::State1, Event::Event2);
+        let mut states = stm.subscribe();
+        let mut records = stm.subscribe_transitions();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // given
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+
+        // when
+        // Event2 is deferred in State1 and processed after switching to Idle.
+        let _ = sender.send(Event::Event2).await;
+        let _ = sender.send(Event::Event3).await;
+
+        // then
+        // The deferred event is published without changing the state.
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+        let deferred: Vec<(Event, bool, bool)> = (0..4)
+            .map(|_| records.try_recv().unwrap())
+            .map(|record| (record.event, record.fired, record.deferred))
+            .collect();
+        assert_eq!(
+            deferred,
+            vec![
+                (Event::Event1, true, false),
+                (Event::Event2, false, true),
+                (Event::Event3, true, false),
+                (Event::Event2, true, false),
+            ]
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_event_deferred_by_one_region_when_received_then_other_region_processes_it() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.on(State::Idle, Event::Event2, State::State2);
+        stm.on(State::State1, Event::Event3, State::Idle);
+        stm.defer(State::State1, Event::Event2);
+        stm.on(State::Offline, Event::Event2, State::Online);
+        stm.on(State::Online, Event::Event2, State::Offline);
+        stm.add_region(State::Offline);
+        let mut configurations = stm.subscribe_configuration();
+        let mut records = stm.subscribe_transitions();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // given
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(
+            configurations.recv().await.unwrap(),
+            vec![State::State1, State::Offline]
+        );
+
+        // when
+        let _ = sender.send(Event::Event2).await;
+        let _ = sender.send(Event::Event3).await;
+
+        // then
+        // The second region processes Event2 right away, the main one once it leaves State1.
+        assert_eq!(
+            configurations.recv().await.unwrap(),
+            vec![State::State1, State::Offline]
+        );
+        assert_eq!(
+            configurations.recv().await.unwrap(),
+            vec![State::State1, State::Online]
+        );
+        assert_eq!(
+            configurations.recv().await.unwrap(),
+            vec![State::Idle, State::Online]
+        );
+        assert_eq!(
+            configurations.recv().await.unwrap(),
+            vec![State::State2, State::Online]
+        );
+        let records: Vec<(Event, bool, bool)> = (0..5)
+            .map(|_| records.try_recv().unwrap())
+            .map(|record| (record.event, record.fired, record.deferred))
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                (Event::Event1, true, false),
+                (Event::Event2, false, true),
+                (Event::Event2, true, false),
+                (Event::Event3, true, false),
+                (Event::Event2, true, false),
+            ]
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_raised_event_when_external_event_queued_then_raised_event_is_processed_first() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1)
+            .action(|_, data| data.raise(Event::Event2));
+        stm.on(State::State1, Event::Event2, State::State2);
+        stm.on(State::State1, Event::Event3, State::Idle);
+        stm.on(State::State2, Event::Event3, State::State1);
+        let mut states = stm.subscribe();
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+        let _ = sender.send(Event::Event3).await;
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_raised_and_deferred_events_when_state_changes_then_raised_event_is_processed_first(
+    ) {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.defer(State::Idle, Event::Event2);
+        stm.on(State::Idle, Event::Event1, State::State1)
+            .action(|_, data| data.raise(Event::Event3));
+        stm.on(State::State1, Event::Event3, State::State2);
+        stm.on(State::State1, Event::Event2, State::Idle);
+        stm.on(State::State2, Event::Event2, State::Idle);
+        let mut records = stm.subscribe_transitions();
+
+        // when
+        let _ = sender.send(Event::Event2).await;
+        let _ = sender.send(Event::Event1).await;
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // then
+        // The deferred Event2 is recalled only after the raised Event3 is processed.
+        let mut processed = Vec::new();
+        for _ in 0..4 {
+            let record = records.recv().await.unwrap();
+            processed.push((record.event, record.prev_state, record.state, record.fired));
+        }
+        assert_eq!(
+            processed,
+            vec![
+                (Event::Event2, State::Idle, State::Idle, false),
+                (Event::Event1, State::Idle, State::State1, true),
+                (Event::Event3, State::State1, State::State2, true),
+                (Event::Event2, State::State2, State::Idle, true),
+            ]
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_completion_transitions_when_state_entered_then_first_with_holding_guard_fires() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_on_register_callback(|_, data| {
+            data.user_data.event_counter += 1;
+        });
+        stm.add_transition(
+            State::State1,
+            TracedState::boxed(State::State1, &trace, vec![]),
+        );
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.on_completion(State::State1, State::State2)
+            .guard(|data| data.user_data.event_counter > 1);
+        stm.on_completion(State::State1, State::Idle);
+        let mut states = stm.subscribe();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        // State1 is transient, it's never the resting state.
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec![
+                "enter State1",
+                "exit State1 completion Idle",
+                "enter State1",
+                "exit State1 completion State2",
+            ]
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_looped_completion_transitions_when_state_entered_then_loop_is_reported() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.on_completion(State::State1, State::State2);
+        stm.on_completion(State::State2, State::Active);
+        stm.on_completion(State::Active, State::State2);
+        let mut records = stm.subscribe_transitions();
+        let mut notifications = stm.subscribe_notifications();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        let record = records.recv().await.unwrap();
+        assert_eq!(
+            record.error.as_deref(),
+            Some("completion transitions looped, returned to State1")
+        );
+        assert_eq!(
+            notifications.recv().await.unwrap(),
+            Notification::CompletionLooped(State::State1)
+        );
+        // The chain is abandoned in the state it started from.
+        assert_eq!((record.state, record.fired), (State::State1, true));
+
+        task.abort();
+    }
+
+    async fn create_choice_stm(
+        dynamic: bool,
+    ) -> (
+        JoinHandle<()>,
+        EventSender<Event, State>,
+        tokio::sync::broadcast::Receiver<State>,
+    ) {
+        let (mut stm, event_sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::Decision)
+            .action(|_, data| data.user_data.event_counter += 1);
+        stm.on(State::Idle, Event::Event2, State::Decision);
+        let choice = if dynamic {
+            stm.add_choice(State::Decision)
+        } else {
+            stm.add_junction(State::Decision)
+        };
+        choice
+            .when(|data| data.user_data.event_counter > 0, State::State1)
+            .when(|data| data.event == Some(Event::Event1), State::State2);
+        let sub = stm.subscribe();
+        let task = tokio::spawn(async move {
+            let _ = stm.process().await;
+        });
+        (task, event_sender, sub)
+    }
+
+    #[tokio::test]
+    async fn given_choice_when_transition_fires_then_guards_see_the_action_result() {
+        let (task, sender, mut states) = create_choice_stm(true).await;
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_junction_when_transition_fires_then_guards_are_evaluated_before_the_action() {
+        let (task, sender, mut states) = create_choice_stm(false).await;
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_junction_when_no_guard_holds_then_transition_does_not_fire() {
+        let (task, sender, mut states) = create_choice_stm(false).await;
+
+        // when
+        let _ = sender.send(Event::Event2).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_choice_within_composite_state_when_transition_fires_then_only_source_is_exited()
+    {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::with_initial(
+            100,
+            State::State1,
+            UserData::default(),
+        );
+        stm.add_transition(
+            State::Active,
+            TracedState::boxed(State::Active, &trace, vec![]),
+        );
+        stm.add_transition(
+            State::State1,
+            TracedState::boxed(State::State1, &trace, vec![]),
+        );
+        stm.add_transition(
+            State::State2,
+            TracedState::boxed(State::State2, &trace, vec![]),
+        );
+        stm.set_parent(State::State1, State::Active);
+        stm.set_parent(State::State2, State::Active);
+        stm.set_parent(State::Decision, State::Active);
+        stm.on(State::State1, Event::Event1, State::Decision);
+        stm.add_choice(State::Decision).otherwise(State::State2);
+        let mut states = stm.subscribe();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec![
+                "enter Active",
+                "enter State1",
+                "exit State1 Event1 Decision",
+                "enter State2",
+            ]
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_junction_when_no_guard_holds_then_event_is_unhandled() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event2, State::Decision);
+        stm.add_junction(State::Decision)
+            .when(|data| data.user_data.event_counter > 0, State::State1);
+        stm.on(State::Idle, Event::Event1, State::State2);
+        stm.on_completion(State::State2, State::Decision);
+        stm.set_unhandled_policy(UnhandledPolicy::DeadLetter);
+        let mut records = stm.subscribe_transitions();
+        let mut dead_letters = stm.subscribe_dead_letters();
+        let task = tokio::spawn(async move {
+            let _ = stm.process().await;
+        });
+
+        // when
+        let _ = sender.send(Event::Event2).await;
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        let record = records.recv().await.unwrap();
+        assert_eq!(record.state, State::Idle);
+        assert!(!record.fired);
+        assert_eq!(dead_letters.recv().await.unwrap().event, Event::Event2);
+        // The completion transition into the junction doesn't fire, the machine rests in its source.
+        let record = records.recv().await.unwrap();
+        assert_eq!(record.state, State::State2);
+        assert!(record.fired);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_final_state_when_it_is_reached_then_process_returns_final_data() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_on_register_callback(|_, data| {
+            data.user_data.event_counter += 1;
+        });
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.on(State::State1, Event::Event2, State::State2);
+        stm.set_final(State::State2);
+        let mut notifications = stm.subscribe_notifications();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+        let _ = sender.send(Event::Event2).await;
+        // The event queued after the final state is not processed.
+        let _ = sender.send(Event::Event3).await;
+
+        // then
+        let termination = task.await.unwrap().unwrap();
+        assert_eq!(termination.reason, TerminationReason::Final(State::State2));
+        assert_eq!(termination.data.state, State::State2);
+        assert_eq!(termination.data.user_data.event_counter, 2);
+        assert_eq!(
+            notifications.recv().await.unwrap(),
+            Notification::Terminated(TerminationReason::Final(State::State2))
+        );
+        assert!(sender.send(Event::Event1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn given_regions_when_one_of_them_is_final_then_machine_keeps_running() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_region(State::Offline);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.on(State::Offline, Event::Event2, State::Online);
+        stm.set_final(State::State1);
+        stm.set_final(State::Online);
+        let mut states = stm.subscribe();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+        assert!(!task.is_finished());
+        let _ = sender.send(Event::Event2).await;
+
+        // then
+        let termination = task.await.unwrap().unwrap();
+        assert_eq!(termination.reason, TerminationReason::Final(State::State1));
+        assert_eq!(
+            termination.data.configuration,
+            vec![State::State1, State::Online]
+        );
+    }
+
+    #[tokio::test]
+    async fn given_running_machine_when_senders_are_dropped_then_process_returns_closed() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        let mut notifications = stm.subscribe_notifications();
+        stm.on(State::Idle, Event::Event1, State::State1);
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        drop(sender);
+
+        // then
+        let termination = task.await.unwrap().unwrap();
+        assert_eq!(termination.reason, TerminationReason::Closed);
+        assert_eq!(
+            notifications.recv().await.unwrap(),
+            Notification::Terminated(TerminationReason::Closed)
+        );
+    }
+
+    #[tokio::test]
+    async fn given_synchronous_producer_when_events_sent_blocking_then_they_are_processed() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(1);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.on(State::State1, Event::Event2, State::State2);
+        stm.set_final(State::State2);
+
+        // when
+        let producer = std::thread::spawn(move || {
+            sender.blocking_send(Event::Event1).unwrap();
+            sender.blocking_send(Event::Event2).unwrap();
+        });
+        let termination = stm.process().await.unwrap();
+
+        // then
+        producer.join().unwrap();
+        assert_eq!(termination.reason, TerminationReason::Final(State::State2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_full_queue_when_event_sent_with_timeout_then_it_times_out() {
+        // given
+        let (_stm, sender) = StateMachine::<Event, State, UserData>::new(1);
+        sender.try_send(Event::Event1).unwrap();
+
+        // when
+        let sent = sender
+            .send_timeout(Event::Event2, Duration::from_secs(1))
+            .await;
+
+        // then
+        assert_eq!(sent, Err(SendTimeoutError::Timeout(Event::Event2)));
+        assert!(format!("{sender:?}").starts_with("EventSender"));
+    }
+
+    async fn create_shutdown_stm(
+        trace: &Arc<Mutex<Vec<String>>>,
+    ) -> (
+        JoinHandle<Result<Termination<Event, State, UserData>, Failure<Event, State, UserData>>>,
+        EventSender<Event, State>,
+        ShutdownHandle,
+    ) {
+        let (mut stm, event_sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_on_register_callback(|_, data| {
+            data.user_data.event_counter += 1;
+        });
+        stm.add_transition(
+            State::Idle,
+            TracedState::boxed(State::Idle, trace, vec![(Event::Event1, State::State1)]),
+        );
+        stm.add_transition(
+            State::State1,
+            TracedState::boxed(State::State1, trace, vec![]),
+        );
+        stm.add_transition(
+            State::Active,
+            TracedState::boxed(State::Active, trace, vec![]),
+        );
+        stm.set_parent(State::State1, State::Active);
+        let shutdown = stm.shutdown_handle();
+        let task = tokio::spawn(async move { stm.process().await });
+        (task, event_sender, shutdown)
+    }
+
+    #[tokio::test]
+    async fn given_queued_events_when_drain_shutdown_then_events_are_processed_and_states_exited() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let (task, sender, shutdown) = create_shutdown_stm(&trace).await;
+        let _ = sender.send(Event::Event1).await;
+        let _ = sender.send(Event::Event2).await;
+
+        // when
+        assert!(shutdown.shutdown(Shutdown::Drain));
+
+        // then
+        let termination = task.await.unwrap().unwrap();
+        assert_eq!(
+            termination.reason,
+            TerminationReason::Shutdown(Shutdown::Drain)
+        );
+        assert_eq!(termination.data.state, State::State1);
+        assert_eq!(termination.data.user_data.event_counter, 2);
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec![
+                "enter Idle",
+                "next Idle Event1",
+                "exit Idle Event1 State1",
+                "enter Active",
+                "enter State1",
+                "next State1 Event2",
+                "next Active Event2",
+                "exit State1 completion State1",
+                "exit Active completion Active",
+            ]
+        );
+        assert!(sender.send(Event::Event1).await.is_err());
+        assert!(!shutdown.shutdown(Shutdown::Immediate));
+    }
+
+    #[tokio::test]
+    async fn given_queued_events_when_immediate_shutdown_then_events_are_dropped() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let (task, sender, shutdown) = create_shutdown_stm(&trace).await;
+        let _ = sender.send(Event::Event1).await;
+
+        // when
+        shutdown.shutdown(Shutdown::Immediate);
+
+        // then
+        let termination = task.await.unwrap().unwrap();
+        assert_eq!(
+            termination.reason,
+            TerminationReason::Shutdown(Shutdown::Immediate)
+        );
+        assert_eq!(termination.data.state, State::Idle);
+        assert_eq!(termination.data.user_data.event_counter, 0);
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec!["enter Idle", "exit Idle completion Idle"]
+        );
+    }
+
+    #[tokio::test]
+    async fn given_deferred_request_when_drain_shutdown_then_it_is_answered_and_dead_lettered() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.defer(State::Idle, Event::Event2);
+        stm.set_unhandled_policy(UnhandledPolicy::DeadLetter);
+        let requester = stm.requester();
+        let shutdown = stm.shutdown_handle();
+        let mut dead_letters = stm.subscribe_dead_letters();
+        let mut records = stm.subscribe_transitions();
+        let task = tokio::spawn(async move { stm.process().await });
+        let request = tokio::spawn(async move { requester.request(Event::Event2).await });
+        assert!(records.recv().await.unwrap().deferred);
+        let _ = sender.send(Event::Event3).await;
+
+        // when
+        shutdown.shutdown(Shutdown::Drain);
+
+        // then
+        let record = request.await.unwrap().unwrap();
+        assert_eq!(
+            (record.event, record.state, record.fired, record.deferred),
+            (Event::Event2, State::Idle, false, true)
+        );
+        assert_eq!(
+            record.error.as_deref(),
+            Some("deferred event dropped, the state machine has stopped")
+        );
+        let dead_letter = dead_letters.recv().await.unwrap();
+        assert_eq!(dead_letter.event, Event::Event3);
+        let dead_letter = dead_letters.recv().await.unwrap();
+        assert_eq!(dead_letter.event, Event::Event2);
+        assert_eq!(dead_letter.configuration, vec![State::Idle]);
+        assert!(task.await.unwrap().is_ok());
+    }
+
+    struct FailingState;
+
+    #[async_trait]
+    impl TryTransition<Event, State, UserData> for FailingState {
+        async fn try_next(
+            &mut self,
+            event: Event,
+            data: &mut Data<Event, State, UserData>,
+        ) -> Result<State, TransitionError> {
+            match event {
+                Event::Event1 => Ok(State::State1),
+                Event::Event2 => Err("sensor disconnected".into()),
+                _ => Ok(data.state),
+            }
+        }
+    }
+
+    async fn create_failing_stm(
+        policy: ErrorPolicy<State>,
+    ) -> (
+        JoinHandle<Result<Termination<Event, State, UserData>, Failure<Event, State, UserData>>>,
+        EventSender<Event, State>,
+        tokio::sync::broadcast::Receiver<State>,
+        tokio::sync::broadcast::Receiver<Notification<State>>,
+    ) {
+        let (mut stm, event_sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_try_transition(State::Idle, Box::new(FailingState {}));
+        stm.set_error_policy(policy);
+        let states = stm.subscribe();
+        let notifications = stm.subscribe_notifications();
+        let task = tokio::spawn(async move { stm.process().await });
+        (task, event_sender, states, notifications)
+    }
+
+    #[tokio::test]
+    async fn given_stay_policy_when_transition_fails_then_state_remains_the_same() {
+        let (task, sender, mut states, mut notifications) =
+            create_failing_stm(ErrorPolicy::Stay).await;
+
+        // when
+        let _ = sender.send(Event::Event2).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+        assert_eq!(
+            notifications.recv().await.unwrap(),
+            Notification::Failed {
+                state: State::Idle,
+                error: "sensor disconnected".to_string()
+            }
+        );
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_go_to_policy_when_transition_fails_then_state_changes_to_error_state() {
+        let (task, sender, mut states, _notifications) =
+            create_failing_stm(ErrorPolicy::GoTo(State::State2)).await;
+
+        // when
+        let _ = sender.send(Event::Event2).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::State2);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_stop_policy_when_transition_fails_then_process_returns_the_error() {
+        let (task, sender, _states, mut notifications) =
+            create_failing_stm(ErrorPolicy::Stop).await;
+
+        // when
+        let _ = sender.send(Event::Event2).await;
+
+        // then
+        let failure = task.await.unwrap().unwrap_err();
+        assert_eq!(failure.error.to_string(), "sensor disconnected");
+        assert_eq!(
+            failure.termination.reason,
+            TerminationReason::Failed(State::Idle)
+        );
+        assert_eq!(failure.termination.data.state, State::Idle);
+        assert!(matches!(
+            notifications.recv().await.unwrap(),
+            Notification::Failed { .. }
+        ));
+        assert_eq!(
+            notifications.recv().await.unwrap(),
+            Notification::Terminated(TerminationReason::Failed(State::Idle))
+        );
+    }
+
+    /// Panics when entered, exited or on the Event2.
+    struct PanickingState {
+        panic_on_enter: bool,
+        panic_on_exit: bool,
+    }
+
+    #[async_trait]
+    impl Transition<Event, State, UserData> for PanickingState {
+        async fn next(&mut self, event: Event, data: &mut Data<Event, State, UserData>) -> State {
+            match event {
+                Event::Event2 => panic!("boom"),
+                _ => data.state,
+            }
+        }
+
+        fn enter(&mut self, _data: &mut Data<Event, State, UserData>) {
+            if self.panic_on_enter {
+                panic!("boom on enter");
+            }
+        }
+
+        fn exit(
+            &mut self,
+            _event: Option<Event>,
+            _next: State,
+            _data: &mut Data<Event, State, UserData>,
+        ) {
+            if self.panic_on_exit {
+                panic!("boom on exit");
+            }
+        }
+    }
+
+    async fn create_panicking_stm(
+        policy: PanicPolicy,
+        panic_on_enter: bool,
+    ) -> (
+        JoinHandle<Result<Termination<Event, State, UserData>, Failure<Event, State, UserData>>>,
+        EventSender<Event, State>,
+        tokio::sync::broadcast::Receiver<State>,
+        tokio::sync::broadcast::Receiver<Notification<State>>,
+    ) {
+        let (mut stm, event_sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_on_register_callback(|_, data| {
+            data.user_data.event_counter += 1;
+        });
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.add_transition(
+            State::State1,
+            Box::new(PanickingState {
+                panic_on_enter,
+                panic_on_exit: false,
+            }),
+        );
+        stm.set_panic_policy(policy);
+        let states = stm.subscribe();
+        let notifications = stm.subscribe_notifications();
+        let task = tokio::spawn(async move { stm.process().await });
+        (task, event_sender, states, notifications)
+    }
+
+    #[tokio::test]
+    async fn given_reset_policy_when_enter_panics_then_previous_state_is_restored() {
+        let (task, sender, mut states, mut notifications) =
+            create_panicking_stm(PanicPolicy::ResetToPrevious, true).await;
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        assert_eq!(
+            notifications.recv().await.unwrap(),
+            Notification::Panicked {
+                state: State::State1,
+                message: "boom on enter".to_string()
+            }
+        );
+        let _ = sender.send(Event::Event3).await;
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_restart_policy_when_transition_panics_then_machine_restarts_with_fresh_data() {
+        let (task, sender, mut states, _notifications) =
+            create_panicking_stm(PanicPolicy::Restart, false).await;
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+
+        // when
+        let _ = sender.send(Event::Event2).await;
+
+        // then
+        let _ = sender.send(Event::Event3).await;
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+        drop(sender);
+        let termination = task.await.unwrap().unwrap();
+        // Only the event received after the restart is counted.
+        assert_eq!(termination.data.user_data.event_counter, 1);
+    }
+
+    #[tokio::test]
+    async fn given_reset_policy_when_requested_event_panics_then_record_holds_the_panic() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.add_transition(
+            State::State1,
+            Box::new(PanickingState {
+                panic_on_enter: false,
+                panic_on_exit: false,
+            }),
+        );
+        stm.set_panic_policy(PanicPolicy::ResetToPrevious);
+        let requester = stm.requester();
+        let mut states = stm.subscribe();
+        let task = tokio::spawn(async move { stm.process().await });
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+
+        // when
+        let record = requester.request(Event::Event2).await.unwrap();
+
+        // then
+        assert_eq!(
+            (record.event, record.prev_state, record.state, record.fired),
+            (Event::Event2, State::State1, State::State1, false)
+        );
+        assert_eq!(record.error.as_deref(), Some("boom"));
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_reset_policy_when_recalled_event_panics_then_published_transition_is_kept() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.defer(State::Idle, Event::Event2);
+        stm.add_transition(
+            State::State1,
+            Box::new(PanickingState {
+                panic_on_enter: false,
+                panic_on_exit: false,
+            }),
+        );
+        stm.set_panic_policy(PanicPolicy::ResetToPrevious);
+        let mut records = stm.subscribe_transitions();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let _ = sender.send(Event::Event2).await;
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        let mut processed = Vec::new();
+        for _ in 0..3 {
+            let record = records.recv().await.unwrap();
+            processed.push((record.event, record.prev_state, record.state, record.fired));
+        }
+        assert_eq!(
+            processed,
+            vec![
+                (Event::Event2, State::Idle, State::Idle, false),
+                (Event::Event1, State::Idle, State::State1, true),
+                // The recalled event panicked, the machine is reset to the state active before it.
+                (Event::Event2, State::State1, State::State1, false),
+            ]
+        );
+        drop(sender);
+        let termination = task.await.unwrap().unwrap();
+        assert_eq!(termination.data.state, State::State1);
+    }
+
+    #[tokio::test]
+    async fn given_reset_policy_when_enter_panics_then_left_state_is_entered_again() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_transition(State::Idle, TracedState::boxed(State::Idle, &trace, vec![]));
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.add_transition(
+            State::State1,
+            Box::new(PanickingState {
+                panic_on_enter: true,
+                panic_on_exit: false,
+            }),
+        );
+        stm.set_panic_policy(PanicPolicy::ResetToPrevious);
+        let mut states = stm.subscribe();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        assert_eq!(states.recv().await.unwrap(), State::Idle);
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec!["enter Idle", "exit Idle Event1 State1", "enter Idle"]
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_restart_policy_when_transition_panics_then_active_states_are_exited() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_transition(State::Idle, TracedState::boxed(State::Idle, &trace, vec![]));
+        stm.add_transition(
+            State::Active,
+            TracedState::boxed(State::Active, &trace, vec![]),
+        );
+        stm.add_transition(
+            State::State1,
+            Box::new(PanickingState {
+                panic_on_enter: false,
+                panic_on_exit: false,
+            }),
+        );
+        stm.set_parent(State::State1, State::Active);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.set_panic_policy(PanicPolicy::Restart);
+        let mut states = stm.subscribe();
+        let task = tokio::spawn(async move { stm.process().await });
+        let _ = sender.send(Event::Event1).await;
+        assert_eq!(states.recv().await.unwrap(), State::State1);
+
+        // when
         let _ = sender.send(Event::Event2).await;
 
         // then
         assert_eq!(states.recv().await.unwrap(), State::Idle);
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec![
+                "enter Idle",
+                "exit Idle Event1 State1",
+                "enter Active",
+                "exit Active completion Idle",
+                "enter Idle",
+            ]
+        );
 
         task.abort();
     }
 
     #[tokio::test]
-    async fn given_state1_when_event3_occur_then_state_return_to_idle() {
-        let (task, sender, mut states) = create_stm().await;
+    async fn given_user_data_factory_when_machine_restarts_then_user_data_is_created_by_it() {
+        let initial_counter = 10;
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::with_initial(
+            100,
+            State::Idle,
+            UserData {
+                event_counter: initial_counter,
+            },
+        );
+        stm.set_user_data_factory(move || UserData {
+            event_counter: initial_counter,
+        });
+        stm.add_on_register_callback(|_, data| {
+            data.user_data.event_counter += 1;
+        });
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.add_transition(
+            State::State1,
+            Box::new(PanickingState {
+                panic_on_enter: false,
+                panic_on_exit: false,
+            }),
+        );
+        stm.set_panic_policy(PanicPolicy::Restart);
+        let task = tokio::spawn(async move { stm.process().await });
 
-        // given
+        // when
+        let _ = sender.send(Event::Event1).await;
+        let _ = sender.send(Event::Event2).await;
+        let _ = sender.send(Event::Event3).await;
+        drop(sender);
+
+        // then
+        let termination = task.await.unwrap().unwrap();
+        assert_eq!(
+            termination.data.user_data.event_counter,
+            initial_counter + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn given_restart_policy_when_exit_panics_on_shutdown_then_panic_is_reported() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.set_parent(State::State1, State::Active);
+        stm.add_transition(
+            State::State1,
+            Box::new(PanickingState {
+                panic_on_enter: false,
+                panic_on_exit: true,
+            }),
+        );
+        stm.add_transition(
+            State::Active,
+            TracedState::boxed(State::Active, &trace, vec![]),
+        );
+        stm.set_panic_policy(PanicPolicy::Restart);
+        let shutdown = stm.shutdown_handle();
+        let mut notifications = stm.subscribe_notifications();
+        let task = tokio::spawn(async move { stm.process().await });
+        let _ = sender.send(Event::Event1).await;
+
+        // when
+        shutdown.shutdown(Shutdown::Drain);
+
+        // then
+        let termination = task.await.unwrap().unwrap();
+        assert_eq!(
+            termination.reason,
+            TerminationReason::Shutdown(Shutdown::Drain)
+        );
+        assert_eq!(
+            notifications.recv().await.unwrap(),
+            Notification::Panicked {
+                state: State::State1,
+                message: "boom on exit".to_string()
+            }
+        );
+        // The ancestor is still exited.
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec!["enter Active", "exit Active completion Active"]
+        );
+    }
+
+    #[tokio::test]
+    async fn given_propagate_policy_when_transition_panics_then_task_panics() {
+        let (task, sender, mut states, mut notifications) =
+            create_panicking_stm(PanicPolicy::Propagate, false).await;
         let _ = sender.send(Event::Event1).await;
         assert_eq!(states.recv().await.unwrap(), State::State1);
+
+        // when
         let _ = sender.send(Event::Event2).await;
-        assert_eq!(states.recv().await.unwrap(), State::State2);
 
-        //when
+        // then
+        assert!(task.await.unwrap_err().is_panic());
+        assert_eq!(
+            notifications.recv().await.unwrap(),
+            Notification::Panicked {
+                state: State::State1,
+                message: "boom".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn given_transition_subscription_when_events_processed_then_records_tell_what_fired() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.on(State::State1, Event::Event3, State::State1);
+        let mut records = stm.subscribe_transitions();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let _ = sender.send(Event::Event2).await;
+        let _ = sender.send(Event::Event1).await;
         let _ = sender.send(Event::Event3).await;
 
         // then
-        assert_eq!(states.recv().await.unwrap(), State::Idle);
+        let mut summary = Vec::new();
+        for _ in 0..3 {
+            let record = records.recv().await.unwrap();
+            assert!(record.processed_at >= record.received_at);
+            summary.push((
+                record.sequence,
+                record.event,
+                record.prev_state,
+                record.state,
+                record.fired,
+            ));
+        }
+        assert_eq!(
+            summary,
+            vec![
+                (1, Event::Event2, State::Idle, State::Idle, false),
+                (2, Event::Event1, State::Idle, State::State1, true),
+                // The internal transition doesn't change the state, but it fires.
+                (3, Event::Event3, State::State1, State::State1, true),
+            ]
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_queued_events_when_processed_later_then_records_keep_the_time_they_were_received(
+    ) {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.on(State::State1, Event::Event2, State::State2);
+        stm.defer(State::Idle, Event::Event2);
+        let mut records = stm.subscribe_transitions();
+        let sent = Instant::now();
+        let _ = sender.send(Event::Event2).await;
+        tokio::time::advance(Duration::from_secs(5)).await;
+        let _ = sender.send(Event::Event1).await;
+        tokio::time::advance(Duration::from_secs(5)).await;
+
+        // when
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // then
+        let deferred = records.recv().await.unwrap();
+        assert!(deferred.deferred);
+        assert_eq!(deferred.received_at, sent);
+        let record = records.recv().await.unwrap();
+        assert_eq!(record.event, Event::Event1);
+        assert_eq!(record.received_at, sent + Duration::from_secs(5));
+        // The recalled event keeps the time it was received first.
+        let recalled = records.recv().await.unwrap();
+        assert_eq!((recalled.event, recalled.fired), (Event::Event2, true));
+        assert_eq!(recalled.received_at, sent);
+        assert_eq!(recalled.processed_at, sent + Duration::from_secs(10));
 
         task.abort();
     }
 
+    #[derive(Debug, Default, Clone, PartialEq)]
+    struct CountingData {
+        event_counter: u64,
+    }
+
     #[tokio::test]
-    async fn given_state2_when_events_counter_exceeded_then_state_return_to_idle() {
-        let (task, sender, mut states) = create_stm().await;
+    async fn given_state_handle_when_state_is_reached_then_waits_return_current_state() {
+        let (mut stm, sender) = StateMachine::<Event, State, CountingData>::new(100);
+        stm.add_on_register_callback(|_, data| {
+            data.user_data.event_counter += 1;
+        });
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.on(State::State1, Event::Event2, State::State2);
+        let states = stm.state_handle_with_user_data();
+        let task = tokio::spawn(async move { stm.process().await });
 
-        // given
+        // when
         let _ = sender.send(Event::Event1).await;
-        assert_eq!(states.recv().await.unwrap(), State::State1);
         let _ = sender.send(Event::Event2).await;
-        assert_eq!(states.recv().await.unwrap(), State::State2);
 
-        //when
-        for _ in 0..3 {
-            let _ = sender.send(Event::Event1).await;
-            assert_eq!(states.recv().await.unwrap(), State::State2);
+        // then
+        assert_eq!(states.wait_for(State::State2).await, Ok(State::State2));
+        // The late handle still sees the current state.
+        let late = states.clone();
+        assert_eq!(late.current(), State::State2);
+        assert_eq!(
+            late.wait_until(|snapshot| snapshot.user_data.as_ref().unwrap().event_counter == 2)
+                .await,
+            Ok(State::State2)
+        );
+        assert_eq!(
+            late.snapshot().user_data,
+            Some(CountingData { event_counter: 2 })
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_state_handle_when_substate_is_reached_then_wait_for_composite_state_returns() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.set_parent(State::State1, State::Active);
+        stm.set_initial_substate(State::Active, State::State1);
+        stm.on(State::Idle, Event::Event1, State::Active);
+        let states = stm.state_handle();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let _ = sender.send(Event::Event1).await;
+
+        // then
+        assert_eq!(
+            states
+                .wait_for_with_timeout(State::Active, Duration::from_secs(1))
+                .await,
+            Ok(State::Active)
+        );
+        assert_eq!(states.current(), State::State1);
+        assert_eq!(
+            states
+                .wait_until(|snapshot| snapshot.active == vec![State::State1, State::Active])
+                .await,
+            Ok(State::State1)
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_machine_not_started_when_handle_is_created_then_it_exposes_initial_states() {
+        let (mut stm, _sender) = StateMachine::<Event, State, CountingData>::with_initial(
+            100,
+            State::Active,
+            CountingData { event_counter: 3 },
+        );
+        let states = stm.state_handle_with_user_data();
+
+        // when
+        stm.set_parent(State::State1, State::Active);
+        stm.set_initial_substate(State::Active, State::State1);
+        stm.add_region(State::Offline);
+
+        // then
+        let snapshot = states.snapshot();
+        assert_eq!(snapshot.state, State::State1);
+        assert_eq!(snapshot.configuration, vec![State::State1, State::Offline]);
+        assert_eq!(
+            snapshot.active,
+            vec![State::State1, State::Active, State::Offline]
+        );
+        assert_eq!(snapshot.user_data, Some(CountingData { event_counter: 3 }));
+        assert_eq!(states.wait_for(State::Active).await, Ok(State::Active));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_state_handle_when_state_is_not_reached_then_wait_fails() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        let states = stm.state_handle();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let timed_out = states
+            .wait_for_with_timeout(State::State1, Duration::from_secs(1))
+            .await;
+        drop(sender);
+        let _ = task.await;
+
+        // then
+        assert_eq!(timed_out, Err(WaitError::Timeout));
+        assert_eq!(states.wait_for(State::State1).await, Err(WaitError::Closed));
+        assert_eq!(states.wait_for(State::Idle).await, Ok(State::Idle));
+    }
+
+    #[tokio::test]
+    async fn given_requester_when_event_is_processed_then_its_record_is_returned() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.on(State::Idle, Event::Event1, State::State1);
+        stm.on(State::State1, Event::Event2, State::State2);
+        stm.defer(State::Idle, Event::Event2);
+        let requester = stm.requester();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        // The deferred event is answered once it's recalled.
+        let deferred = tokio::spawn({
+            let requester = requester.clone();
+            async move { requester.request(Event::Event2).await }
+        });
+        tokio::task::yield_now().await;
+        let (record, _) =
+            tokio::join!(requester.request(Event::Event1), sender.send(Event::Event3));
+        let ignored = requester.request(Event::Event1).await.unwrap();
+
+        // then
+        let record = record.unwrap();
+        assert_eq!(
+            (record.event, record.prev_state, record.state, record.fired),
+            (Event::Event1, State::Idle, State::State1, true)
+        );
+        let deferred = deferred.await.unwrap().unwrap();
+        assert_eq!(
+            (deferred.event, deferred.state, deferred.fired),
+            (Event::Event2, State::State2, true)
+        );
+        assert_eq!((ignored.state, ignored.fired), (State::State2, false));
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_request_and_events_when_queued_then_they_are_processed_in_order_of_arrival() {
+        let (stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        let requester = stm.requester();
+        let mut records = stm.subscribe_transitions();
+
+        // given
+        let request = tokio::spawn(async move { requester.request(Event::Event1).await });
+        tokio::task::yield_now().await;
+        let _ = sender.send(Event::Event2).await;
+        let _ = sender.send(Event::Event3).await;
+
+        // when
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // then
+        assert_eq!(request.await.unwrap().unwrap().sequence, 1);
+        let events: Vec<Event> = (0..3).map(|_| records.try_recv().unwrap().event).collect();
+        assert_eq!(events, vec![Event::Event1, Event::Event2, Event::Event3]);
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn given_requester_when_transition_fails_then_error_is_returned() {
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_try_transition(State::Idle, Box::new(FailingState {}));
+        stm.set_error_policy(ErrorPolicy::Stay);
+        let requester = stm.requester();
+        let shutdown = stm.shutdown_handle();
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let record = requester.request(Event::Event2).await.unwrap();
+        // The machine keeps running while the requester is alive.
+        drop(sender);
+        let record2 = requester.request(Event::Event1).await.unwrap();
+        shutdown.shutdown(Shutdown::Immediate);
+        let _ = task.await;
+
+        // then
+        assert_eq!(record.error, Some("sensor disconnected".to_string()));
+        assert!(!record.fired);
+        assert_eq!((record2.state, record2.error), (State::State1, None));
+        assert_eq!(requester.request(Event::Event1).await, Err(RequestError));
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Command {
+        Say(String),
+        Stop,
+    }
+
+    #[derive(Debug, Default)]
+    struct Transcript {
+        said: Vec<String>,
+    }
+
+    struct ListeningState;
+
+    #[async_trait]
+    impl Transition<Command, State, Transcript> for ListeningState {
+        async fn next(
+            &mut self,
+            event: Command,
+            data: &mut Data<Command, State, Transcript>,
+        ) -> State {
+            match event {
+                // The payload is moved out of the event.
+                Command::Say(text) => data.user_data.said.push(text),
+                Command::Stop => return State::State1,
+            }
+            data.state
         }
+    }
+
+    #[tokio::test]
+    async fn given_events_with_owned_payload_when_processed_then_payload_is_delivered() {
+        let (mut stm, sender) = StateMachine::<Command, State, Transcript>::new(100);
+        stm.add_transition(State::Idle, Box::new(ListeningState {}));
+        stm.on(State::State1, Command::Stop, State::Idle);
+        stm.set_final(State::State2);
+        stm.on_match(
+            State::State1,
+            |event| matches!(event, Command::Say(text) if text == "bye"),
+            State::State2,
+        );
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        for command in [
+            Command::Say("hello".to_string()),
+            Command::Say("world".to_string()),
+            Command::Stop,
+            Command::Say("bye".to_string()),
+        ] {
+            let _ = sender.send(command).await;
+        }
+
+        // then
+        let data = task.await.unwrap().unwrap().data;
+        assert_eq!(data.user_data.said, vec!["hello", "world"]);
+        assert_eq!(data.event, Some(Command::Say("bye".to_string())));
+        // The events carrying the different data are recorded as the same kind.
+        assert_eq!(data.events.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn given_event_history_size_when_more_events_received_then_only_recent_ones_are_kept() {
+        let (mut stm, sender) = StateMachine::<Command, State, Transcript>::new(100);
+        stm.set_event_history_size(2);
+        stm.set_final(State::State1);
+        let say = discriminant(&Command::Say(String::new()));
+        stm.on(State::Idle, Command::Stop, State::State1)
+            .guard(move |_, data| data.last_seen_kind(say).is_some());
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        // The guard doesn't hold until any Say command is received.
+        let _ = sender.send(Command::Stop).await;
+        let _ = sender.send(Command::Say("hello".to_string())).await;
+        let _ = sender.send(Command::Say("world".to_string())).await;
+        let _ = sender.send(Command::Stop).await;
+
+        // then
+        let data = task.await.unwrap().unwrap().data;
+        let history: Vec<Command> = data
+            .event_history()
+            .map(|(event, _)| event.clone())
+            .collect();
+        assert_eq!(
+            history,
+            vec![Command::Say("world".to_string()), Command::Stop]
+        );
+        assert!(
+            data.last_seen(&Command::Say("any".to_string())).unwrap()
+                <= data.last_seen(&Command::Stop).unwrap()
+        );
+        assert_eq!(data.events.len(), 2);
+    }
+
+    #[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+    enum Door {
+        Closed,
+        Open,
+    }
+
+    #[derive(Debug)]
+    struct Config {
+        name: String,
+    }
+
+    #[tokio::test]
+    async fn given_initial_state_and_user_data_when_created_then_machine_starts_with_them() {
+        let config = Config {
+            name: "front".to_string(),
+        };
+        let (mut stm, sender) =
+            StateMachine::<Event, Door, Config>::with_initial(100, Door::Open, config);
+        stm.on(Door::Open, Event::Event1, Door::Closed);
+        stm.on(Door::Closed, Event::Event2, Door::Open);
+        stm.set_final(Door::Closed);
+        let states = stm.state_handle();
+        assert_eq!(states.current(), Door::Open);
+        let task = tokio::spawn(async move { stm.process().await });
+
+        // when
+        let _ = sender.send(Event::Event2).await;
         let _ = sender.send(Event::Event1).await;
 
         // then
-        // after 5th event stae should get back to Idle.
-        assert_eq!(states.recv().await.unwrap(), State::Idle);
+        let data = task.await.unwrap().unwrap().data;
+        assert_eq!(data.prev_state, Some(Door::Open));
+        assert_eq!(data.state, Door::Closed);
+        assert_eq!(data.user_data.name, "front");
+    }
+
+    #[tokio::test]
+    async fn given_valid_state_graph_when_built_then_machine_is_created() {
+        // given
+        let builder =
+            StateMachine::<Event, State, UserData>::builder(100, State::Idle, UserData::default())
+                .transition(State::Idle, Box::new(IdleState {}))
+                .transition(State::State1, Box::new(State1State {}))
+                .configure(|stm| {
+                    stm.on(State::State1, Event::Event2, State::State2);
+                    stm.set_final(State::State2);
+                });
+
+        // when
+        let (stm, sender) = builder.build().unwrap();
+        let task = tokio::spawn(async move { stm.process().await });
+        let _ = sender.send(Event::Event1).await;
+        let _ = sender.send(Event::Event2).await;
+
+        // then
+        let termination = task.await.unwrap().unwrap();
+        assert_eq!(termination.reason, TerminationReason::Final(State::State2));
+    }
+
+    #[test]
+    fn given_invalid_state_graph_when_built_then_all_problems_are_listed() {
+        // given
+        let builder =
+            StateMachine::<Event, State, UserData>::builder(100, State::Idle, UserData::default())
+                .transition(State::Idle, Box::new(IdleState {}))
+                .transition(State::Idle, Box::new(IdleState {}))
+                .region(State::Offline)
+                .configure(|stm| {
+                    stm.on(State::Idle, Event::Event2, State::Decision);
+                    stm.add_choice(State::Decision)
+                        .when(|data| data.user_data.event_counter > 0, State::State1)
+                        .otherwise(State::State2);
+                    stm.on(State::State2, Event::Event3, State::Idle);
+                });
+
+        // when
+        let error = builder.build().err().unwrap();
+
+        // then
+        assert_eq!(
+            error.problems,
+            vec![
+                BuildProblem::DuplicateTransition(State::Idle),
+                BuildProblem::UnregisteredInitialState(State::Offline),
+                BuildProblem::UnregisteredState(State::State1),
+            ]
+        );
+        assert_eq!(
+            error.to_string(),
+            "invalid state machine: duplicate transition of Idle; \
+            initial state Offline is not registered; reachable state State1 is not registered"
+        );
+    }
+
+    #[test]
+    fn given_error_state_when_built_then_it_is_validated_as_reachable() {
+        // given
+        let builder =
+            StateMachine::<Event, State, UserData>::builder(100, State::Idle, UserData::default())
+                .try_transition(State::Idle, Box::new(FailingState))
+                .try_transition(State::Idle, Box::new(FailingState))
+                .try_transition(State::Idle, Box::new(FailingState))
+                .transition(State::State1, Box::new(State1State {}))
+                .configure(|stm| {
+                    stm.set_final(State::State2);
+                    stm.set_error_policy(ErrorPolicy::GoTo(State::Offline));
+                });
+
+        // when
+        let error = builder.build().err().unwrap();
+
+        // then
+        assert_eq!(
+            error.problems,
+            vec![
+                BuildProblem::DuplicateTransition(State::Idle),
+                BuildProblem::UnregisteredState(State::Offline),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn given_dead_letter_policy_when_event_is_unhandled_then_it_is_sent_to_dead_letters() {
+        // given
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::new(100);
+        stm.add_transition(State::Idle, Box::new(IdleState {}));
+        stm.set_unhandled_policy(UnhandledPolicy::DeadLetter);
+        let mut dead_letters = stm.subscribe_dead_letters();
+        let mut records = stm.subscribe_transitions();
+        let task = tokio::spawn(async move {
+            let _ = stm.process().await;
+        });
+
+        // when
+        let _ = sender.send(Event::Event2).await;
+        let _ = sender.send(Event::Event1).await;
+        let _ = sender.send(Event::Event3).await;
+
+        // then
+        let dead_letter = dead_letters.recv().await.unwrap();
+        assert_eq!(dead_letter.event, Event::Event2);
+        assert_eq!(dead_letter.configuration, vec![State::Idle]);
+        let dead_letter = dead_letters.recv().await.unwrap();
+        assert_eq!(dead_letter.event, Event::Event3);
+        assert_eq!(dead_letter.configuration, vec![State::State1]);
+        assert!(!records.recv().await.unwrap().fired);
+        assert!(records.recv().await.unwrap().fired);
+
+        task.abort();
+    }
+
+    struct StayingState;
+
+    #[async_trait]
+    impl Transition<Event, State, UserData> for StayingState {
+        async fn next(&mut self, event: Event, data: &mut Data<Event, State, UserData>) -> State {
+            if event == Event::Event3 {
+                data.stay();
+            }
+            data.state
+        }
+    }
+
+    #[tokio::test]
+    async fn given_transition_staying_in_state_when_event_is_handled_then_it_is_not_unhandled() {
+        // given
+        let (mut stm, sender) = StateMachine::<Event, State, UserData>::with_initial(
+            100,
+            State::State1,
+            UserData::default(),
+        );
+        stm.set_parent(State::State1, State::Active);
+        stm.add_transition(State::State1, Box::new(StayingState {}));
+        stm.on(State::Active, Event::Event3, State::Idle);
+        stm.set_unhandled_policy(UnhandledPolicy::DeadLetter);
+        stm.set_state_unhandled_policy(State::Active, UnhandledPolicy::Error);
+        let mut records = stm.subscribe_transitions();
+        let task = tokio::spawn(async move {
+            let _ = stm.process().await;
+        });
+
+        // when
+        let _ = sender.send(Event::Event3).await;
+        let _ = sender.send(Event::Event2).await;
+
+        // then
+        let record = records.recv().await.unwrap();
+        assert_eq!(record.state, State::State1);
+        assert!(record.fired);
+        assert_eq!(record.error, None);
+        let record = records.recv().await.unwrap();
+        assert_eq!(record.state, State::State1);
+        assert!(!record.fired);
+        assert_eq!(
+            record.error.as_deref(),
+            Some("unhandled event Event2 in [State1]")
+        );
 
         task.abort();
     }
@@ -0,0 +1,63 @@
+use crate::TransitionRecord;
+use std::fmt::Display;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+/// The event together with the time it was sent and the sender of the record of its processing, if it's awaited.
+pub(crate) type Request<Event, State> = (Event, Instant, Option<Responder<Event, State>>);
+
+/// Sends back the record of the processed event.
+pub(crate) type Responder<Event, State> = oneshot::Sender<TransitionRecord<Event, State>>;
+
+/// The event was not processed, because the machine has stopped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RequestError;
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the event was not processed, the state machine has stopped"
+        )
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// Sends the events to the machine and awaits the outcome of their processing.
+/// It's created by [requester](crate::StateMachine::requester) and can be cloned to be used by many producers.
+/// The requests share the queue with the events sent by the [EventSender](crate::EventSender).
+/// The machine keeps running as long as any event sender or requester is alive.
+pub struct Requester<Event, State> {
+    sender: mpsc::Sender<Request<Event, State>>,
+}
+
+impl<Event, State> Clone for Requester<Event, State> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<Event, State> Requester<Event, State> {
+    pub(crate) fn new(sender: mpsc::Sender<Request<Event, State>>) -> Self {
+        Self { sender }
+    }
+
+    /// Send the event and wait until it's processed.
+    /// The deferred event is awaited until it's processed after being recalled.
+    /// * `event` - the event to process.
+    /// * return the [TransitionRecord] of the event - the resulting state, whether any transition fired and the error if any.
+    pub async fn request(
+        &self,
+        event: Event,
+    ) -> Result<TransitionRecord<Event, State>, RequestError> {
+        let (responder, response) = oneshot::channel();
+        self.sender
+            .send((event, Instant::now(), Some(responder)))
+            .await
+            .map_err(|_| RequestError)?;
+        response.await.map_err(|_| RequestError)
+    }
+}
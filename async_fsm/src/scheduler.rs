@@ -0,0 +1,113 @@
+use crate::requester::Request;
+use std::time::Duration;
+use tokio::sync::mpsc::WeakSender;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Schedules the events delivered to the StateMachine later on.
+/// It's created by [scheduler](crate::StateMachine::scheduler) and can be cloned to be used by many producers.
+/// The scheduled events are cancelled when the StateMachine is dropped,
+/// and they are not delivered once all the event senders are dropped.
+pub struct Scheduler<Event, State> {
+    sender: WeakSender<Request<Event, State>>,
+    // closed when the StateMachine is dropped
+    running: watch::Receiver<()>,
+}
+
+impl<Event, State> Clone for Scheduler<Event, State> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            running: self.running.clone(),
+        }
+    }
+}
+
+/// Handle of the scheduled event, which allows to cancel its delivery.
+/// Dropping the handle doesn't cancel the event.
+pub struct ScheduledEvent {
+    task: JoinHandle<()>,
+}
+
+impl ScheduledEvent {
+    /// Cancel the delivery of the event, for the periodic event all the next deliveries are cancelled.
+    pub fn cancel(&self) {
+        self.task.abort();
+    }
+
+    /// Check if the event was delivered or cancelled.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl<Event, State> Scheduler<Event, State>
+where
+    Event: Send + 'static,
+    State: Send + 'static,
+{
+    pub(crate) fn new(
+        sender: WeakSender<Request<Event, State>>,
+        running: watch::Receiver<()>,
+    ) -> Self {
+        Self { sender, running }
+    }
+
+    /// Deliver the event after the delay.
+    /// * `event` - the event to deliver.
+    /// * `delay` - the time after which the event is delivered.
+    pub fn send_after(&self, event: Event, delay: Duration) -> ScheduledEvent {
+        self.send_at(event, Instant::now() + delay)
+    }
+
+    /// Deliver the event at the given time.
+    /// * `event` - the event to deliver.
+    /// * `at` - the time when the event is delivered.
+    pub fn send_at(&self, event: Event, at: Instant) -> ScheduledEvent {
+        let sender = self.sender.clone();
+        let mut running = self.running.clone();
+        let task = tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep_until(at) => {
+                    deliver(&sender, event).await;
+                }
+                _ = running.changed() => {}
+            }
+        });
+        ScheduledEvent { task }
+    }
+
+    /// Deliver the event periodically, the first delivery is after the `period`.
+    /// * `event` - the event to deliver.
+    /// * `period` - the time between the deliveries.
+    pub fn send_every(&self, event: Event, period: Duration) -> ScheduledEvent
+    where
+        Event: Clone,
+    {
+        let sender = self.sender.clone();
+        let mut running = self.running.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        if !deliver(&sender, event.clone()).await {
+                            break;
+                        }
+                    }
+                    _ = running.changed() => break,
+                }
+            }
+        });
+        ScheduledEvent { task }
+    }
+}
+
+/// Send the event if any of the event senders is still alive, return true if the event was delivered.
+async fn deliver<Event, State>(sender: &WeakSender<Request<Event, State>>, event: Event) -> bool {
+    match sender.upgrade() {
+        Some(sender) => sender.send((event, Instant::now(), None)).await.is_ok(),
+        None => false,
+    }
+}
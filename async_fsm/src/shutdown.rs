@@ -0,0 +1,32 @@
+use tokio::sync::mpsc::UnboundedSender;
+
+/// The mode of the machine shutdown.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Shutdown {
+    /// Stop right away, the events still queued in the event channel are dropped.
+    Immediate,
+    /// Stop receiving the new events, but process the events already queued in the event channel first.
+    /// The events still [deferred](crate::StateMachine::defer) afterwards are dropped.
+    Drain,
+}
+
+/// Handle stopping the running [process](crate::StateMachine::process).
+/// It's created by [shutdown_handle](crate::StateMachine::shutdown_handle) and can be cloned.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: UnboundedSender<Shutdown>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(sender: UnboundedSender<Shutdown>) -> Self {
+        Self { sender }
+    }
+
+    /// Request the shutdown of the machine, the [process](crate::StateMachine::process) returns
+    /// the [Termination](crate::Termination) once the active states are exited.
+    /// * `mode` - the [Shutdown] mode.
+    /// * return false if the machine is already stopped.
+    pub fn shutdown(&self, mode: Shutdown) -> bool {
+        self.sender.send(mode).is_ok()
+    }
+}
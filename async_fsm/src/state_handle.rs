@@ -0,0 +1,115 @@
+use std::fmt::Display;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// The snapshot of the machine published after every processed event, before the machine is started it holds its initial states.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot<State, UserData> {
+    /// The state of the main region.
+    pub state: State,
+    /// The states of all the orthogonal regions, the first one is the main region.
+    pub configuration: Vec<State>,
+    /// All the active states - the states of the configuration together with their ancestors.
+    pub active: Vec<State>,
+    /// The copy of the UserData, it's set only for the handle created by
+    /// [state_handle_with_user_data](crate::StateMachine::state_handle_with_user_data).
+    pub user_data: Option<UserData>,
+}
+
+/// The reason why the wait for the state has failed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WaitError {
+    /// The machine has stopped before reaching the state.
+    Closed,
+    /// The state was not reached before the timeout.
+    Timeout,
+}
+
+impl Display for WaitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WaitError::Closed => write!(f, "the state machine has stopped"),
+            WaitError::Timeout => write!(f, "the wait for the state has timed out"),
+        }
+    }
+}
+
+impl std::error::Error for WaitError {}
+
+/// The read handle of the current state of the machine.
+/// Unlike the [subscribe](crate::StateMachine::subscribe) it always exposes the current state,
+/// no matter when it was created and how often it's checked.
+/// It's created by [state_handle](crate::StateMachine::state_handle) and can be cloned.
+pub struct StateHandle<State, UserData> {
+    receiver: watch::Receiver<Snapshot<State, UserData>>,
+}
+
+impl<State, UserData> Clone for StateHandle<State, UserData> {
+    fn clone(&self) -> Self {
+        Self {
+            receiver: self.receiver.clone(),
+        }
+    }
+}
+
+impl<State: Copy + PartialEq, UserData> StateHandle<State, UserData> {
+    pub(crate) fn new(receiver: watch::Receiver<Snapshot<State, UserData>>) -> Self {
+        Self { receiver }
+    }
+
+    /// The current state of the main region.
+    pub fn current(&self) -> State {
+        self.receiver.borrow().state
+    }
+
+    /// The current states of all the orthogonal regions.
+    pub fn configuration(&self) -> Vec<State> {
+        self.receiver.borrow().configuration.clone()
+    }
+
+    /// The current snapshot of the machine.
+    pub fn snapshot(&self) -> Snapshot<State, UserData>
+    where
+        UserData: Clone,
+    {
+        self.receiver.borrow().clone()
+    }
+
+    /// Wait until the state is active in any of the orthogonal regions, it returns immediately if it's active already.
+    /// The composite state is active as long as any of its substates is.
+    /// * `state` - the awaited state.
+    pub async fn wait_for(&self, state: State) -> Result<State, WaitError> {
+        self.wait_until(|snapshot| snapshot.active.contains(&state))
+            .await
+            .map(|_| state)
+    }
+
+    /// Wait until the snapshot of the machine fulfills the predicate,
+    /// it returns immediately if the current one does.
+    /// * `predicate` - the closure receiving the snapshot.
+    /// * return the state of the main region fulfilling the predicate.
+    pub async fn wait_until(
+        &self,
+        predicate: impl FnMut(&Snapshot<State, UserData>) -> bool,
+    ) -> Result<State, WaitError> {
+        let mut receiver = self.receiver.clone();
+        let snapshot = receiver
+            .wait_for(predicate)
+            .await
+            .map_err(|_| WaitError::Closed)?;
+        Ok(snapshot.state)
+    }
+
+    /// Wait until the state is active, see [wait_for](StateHandle::wait_for), but no longer than the `timeout`.
+    /// * `state` - the awaited state.
+    /// * `timeout` - the maximal time of the wait.
+    pub async fn wait_for_with_timeout(
+        &self,
+        state: State,
+        timeout: Duration,
+    ) -> Result<State, WaitError> {
+        tokio::time::timeout(timeout, self.wait_for(state))
+            .await
+            .map_err(|_| WaitError::Timeout)?
+    }
+}
@@ -0,0 +1,244 @@
+use crate::Data;
+use std::fmt::{Debug, Display};
+
+/// Decides whether the event triggers the transition.
+pub(crate) type Matcher<Event> = Box<dyn Fn(&Event) -> bool + Send + Sync>;
+
+/// The condition which needs to hold for the transition to fire.
+pub type Guard<Event, State, UserData> =
+    Box<dyn Fn(&Event, &Data<Event, State, UserData>) -> bool + Send + Sync>;
+
+/// The side effect of the transition, executed after leaving the source state and before entering the target one.
+pub type Action<Event, State, UserData> =
+    Box<dyn Fn(&Event, &mut Data<Event, State, UserData>) + Send + Sync>;
+
+/// The transition declared in the transition table of the state.
+/// It's created by [on](crate::StateMachine::on) or [on_match](crate::StateMachine::on_match).
+/// It's displayed as `Source --Event [guard name]--> Target`, f.e. in the log of the processed event.
+pub struct TransitionRow<Event, State, UserData> {
+    source: State,
+    // the event description
+    trigger: String,
+    matcher: Matcher<Event>,
+    guard: Option<Guard<Event, State, UserData>>,
+    guard_name: Option<String>,
+    pub(crate) action: Option<Action<Event, State, UserData>>,
+    pub(crate) target: State,
+}
+
+impl<Event, State, UserData> TransitionRow<Event, State, UserData> {
+    pub(crate) fn new(
+        source: State,
+        trigger: String,
+        matcher: Matcher<Event>,
+        target: State,
+    ) -> Self {
+        Self {
+            source,
+            trigger,
+            matcher,
+            guard: None,
+            guard_name: None,
+            action: None,
+            target,
+        }
+    }
+
+    /// Set the guard of the transition, the transition fires only if the guard returns true.
+    /// The guards of the transitions triggered by the same event are evaluated in the declaration order
+    /// and the first transition with the holding guard fires.
+    /// * `guard` - the closure receiving the event and the state machine shared data.
+    pub fn guard(
+        &mut self,
+        guard: impl Fn(&Event, &Data<Event, State, UserData>) -> bool + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.guard = Some(Box::new(guard));
+        self.guard_name = None;
+        self
+    }
+
+    /// Set the guard of the transition, see [guard](TransitionRow::guard).
+    /// The name is reported in the log when the transition fires.
+    /// * `name` - the guard description, f.e. the condition it checks.
+    /// * `guard` - the closure receiving the event and the state machine shared data.
+    pub fn guard_named(
+        &mut self,
+        name: &str,
+        guard: impl Fn(&Event, &Data<Event, State, UserData>) -> bool + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.guard(guard);
+        self.guard_name = Some(name.to_string());
+        self
+    }
+
+    /// Set the action of the transition.
+    /// * `action` - the closure receiving the event and the mutable state machine shared data.
+    pub fn action(
+        &mut self,
+        action: impl Fn(&Event, &mut Data<Event, State, UserData>) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.action = Some(Box::new(action));
+        self
+    }
+
+    /// Check if the event triggers the transition and the guard holds.
+    pub(crate) fn fires(&self, event: &Event, data: &Data<Event, State, UserData>) -> bool {
+        (self.matcher)(event) && self.guard.as_ref().is_none_or(|guard| guard(event, data))
+    }
+}
+
+impl<Event, State: Debug, UserData> Display for TransitionRow<Event, State, UserData> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} --{}", self.source, self.trigger)?;
+        match (&self.guard_name, &self.guard) {
+            (Some(name), _) => write!(f, " [{name}]")?,
+            (None, Some(_)) => write!(f, " [guard]")?,
+            (None, None) => {}
+        }
+        write!(f, "--> {:?}", self.target)
+    }
+}
+
+/// The condition which needs to hold for the completion transition to fire.
+pub type CompletionGuard<Event, State, UserData> =
+    Box<dyn Fn(&Data<Event, State, UserData>) -> bool + Send + Sync>;
+
+/// The side effect of the completion transition.
+pub type CompletionAction<Event, State, UserData> =
+    Box<dyn Fn(&mut Data<Event, State, UserData>) + Send + Sync>;
+
+/// The completion transition - fired without any event, when the state is entered and the guard holds.
+/// It's created by [on_completion](crate::StateMachine::on_completion).
+/// It's displayed as `Source --[guard name]--> Target`, f.e. in the log of the fired transition.
+pub struct CompletionRow<Event, State, UserData> {
+    source: State,
+    guard: Option<CompletionGuard<Event, State, UserData>>,
+    guard_name: Option<String>,
+    pub(crate) action: Option<CompletionAction<Event, State, UserData>>,
+    pub(crate) target: State,
+}
+
+impl<Event, State, UserData> CompletionRow<Event, State, UserData> {
+    pub(crate) fn new(source: State, target: State) -> Self {
+        Self {
+            source,
+            guard: None,
+            guard_name: None,
+            action: None,
+            target,
+        }
+    }
+
+    /// Set the guard of the completion transition, the transition fires only if the guard returns true.
+    /// The guards are evaluated in the declaration order and the first transition with the holding guard fires.
+    /// * `guard` - the closure receiving the state machine shared data.
+    pub fn guard(
+        &mut self,
+        guard: impl Fn(&Data<Event, State, UserData>) -> bool + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.guard = Some(Box::new(guard));
+        self.guard_name = None;
+        self
+    }
+
+    /// Set the guard of the completion transition, see [guard](CompletionRow::guard).
+    /// The name is reported in the log when the transition fires.
+    /// * `name` - the guard description, f.e. the condition it checks.
+    /// * `guard` - the closure receiving the state machine shared data.
+    pub fn guard_named(
+        &mut self,
+        name: &str,
+        guard: impl Fn(&Data<Event, State, UserData>) -> bool + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.guard(guard);
+        self.guard_name = Some(name.to_string());
+        self
+    }
+
+    /// Set the action of the completion transition.
+    /// * `action` - the closure receiving the mutable state machine shared data.
+    pub fn action(
+        &mut self,
+        action: impl Fn(&mut Data<Event, State, UserData>) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.action = Some(Box::new(action));
+        self
+    }
+
+    /// Check if the guard holds.
+    pub(crate) fn fires(&self, data: &Data<Event, State, UserData>) -> bool {
+        self.guard.as_ref().is_none_or(|guard| guard(data))
+    }
+}
+
+impl<Event, State: Debug, UserData> Display for CompletionRow<Event, State, UserData> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} --", self.source)?;
+        match (&self.guard_name, &self.guard) {
+            (Some(name), _) => write!(f, "[{name}]")?,
+            (None, Some(_)) => write!(f, "[guard]")?,
+            (None, None) => {}
+        }
+        write!(f, "--> {:?}", self.target)
+    }
+}
+
+/// The condition of the choice branch.
+pub type ChoiceGuard<Event, State, UserData> =
+    Box<dyn Fn(&Data<Event, State, UserData>) -> bool + Send + Sync>;
+
+/// The choice or junction pseudo-state selecting the transition target dynamically.
+/// It's created by [add_choice](crate::StateMachine::add_choice) or [add_junction](crate::StateMachine::add_junction).
+pub struct Choice<Event, State, UserData> {
+    // the choice is resolved after leaving the source state, the junction before
+    pub(crate) dynamic: bool,
+    branches: Vec<(ChoiceGuard<Event, State, UserData>, State)>,
+    otherwise: Option<State>,
+}
+
+impl<Event, State: Copy, UserData> Choice<Event, State, UserData> {
+    pub(crate) fn new(dynamic: bool) -> Self {
+        Self {
+            dynamic,
+            branches: Vec::new(),
+            otherwise: None,
+        }
+    }
+
+    /// Add the branch selected when the guard holds.
+    /// The guards are evaluated in the declaration order and the first branch with the holding guard is selected.
+    /// * `guard` - the closure receiving the state machine shared data, [event](Data::event) holds the triggering event.
+    /// * `target` - the state the machine is switching into.
+    pub fn when(
+        &mut self,
+        guard: impl Fn(&Data<Event, State, UserData>) -> bool + Send + Sync + 'static,
+        target: State,
+    ) -> &mut Self {
+        self.branches.push((Box::new(guard), target));
+        self
+    }
+
+    /// Set the branch selected when none of the guards holds.
+    /// * `target` - the state the machine is switching into.
+    pub fn otherwise(&mut self, target: State) -> &mut Self {
+        self.otherwise = Some(target);
+        self
+    }
+
+    /// All the targets the choice can select.
+    pub(crate) fn targets(&self) -> impl Iterator<Item = State> + '_ {
+        self.branches
+            .iter()
+            .map(|(_, target)| *target)
+            .chain(self.otherwise)
+    }
+
+    /// The target of the first branch with the holding guard.
+    pub(crate) fn select(&self, data: &Data<Event, State, UserData>) -> Option<State> {
+        self.branches
+            .iter()
+            .find(|(guard, _)| guard(data))
+            .map(|(_, target)| *target)
+            .or(self.otherwise)
+    }
+}
@@ -1,36 +1,100 @@
+use crate::parser::Uml;
 use askama::Template;
-use std::collections::HashMap;
-use std::collections::HashSet;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::Path;
 
+// The sorted collections keep the generated file the same for the same diagram.
 #[derive(Template)]
 #[template(path = "fsm.txt")]
 struct FsmTemplate {
-    events: HashSet<String>,
-    states: HashSet<String>,
-    transitions: HashMap<String, Vec<(String, String)>>,
+    events: BTreeSet<String>,
+    states: BTreeSet<String>,
+    initial: String,
+    transitions: BTreeMap<String, Vec<(String, String)>>,
+    parents: BTreeMap<String, String>,
+    initial_substates: BTreeMap<String, String>,
+    // choice pseudo-state, Vec<(guard function or "else", dest state)>
+    choices: BTreeMap<String, Vec<(String, String)>>,
+    // guard function, guard description
+    guards: Vec<(String, String)>,
+    finals: BTreeSet<String>,
+    completions: BTreeMap<String, Vec<String>>,
 }
 
-pub fn get_main(
-    events: &HashSet<String>,
-    states: &HashSet<String>,
-    transitions: &HashMap<String, Vec<(String, String)>>,
-) -> String {
+/// Render the main.rs of the state machine.
+/// Return the error if the diagram has no initial state - `[*] --> State` outside of the composite states.
+pub fn get_main(uml: &Uml) -> Result<String, String> {
+    let Some(initial) = uml.initial.clone() else {
+        return Err(
+            "No initial state, expected `[*] --> State` outside of the composite states"
+                .to_string(),
+        );
+    };
+    let mut guards = Vec::new();
+    let mut choices = BTreeMap::new();
+    // The guard functions are numbered in the order of the choices, so they are visited sorted.
+    let sorted_choices: BTreeMap<_, _> = uml.choices.iter().collect();
+    for (choice, branches) in sorted_choices {
+        let branches = branches
+            .iter()
+            .map(|(guard, to)| match guard.as_str() {
+                "else" => (guard.clone(), to.clone()),
+                _ => {
+                    let function = guard_function(guard, &guards);
+                    guards.push((function.clone(), guard.clone()));
+                    (function, to.clone())
+                }
+            })
+            .collect();
+        choices.insert(choice.clone(), branches);
+    }
+
     let fsm_template = FsmTemplate {
-        events: events.clone(),
-        states: states.clone(),
-        transitions: transitions.clone(),
+        events: uml.events.iter().cloned().collect(),
+        states: uml.states.iter().cloned().collect(),
+        initial,
+        transitions: uml.transitions.clone().into_iter().collect(),
+        parents: uml.parents.clone().into_iter().collect(),
+        initial_substates: uml.initial_substates.clone().into_iter().collect(),
+        choices,
+        guards,
+        finals: uml.finals.iter().cloned().collect(),
+        completions: uml.completions.clone().into_iter().collect(),
     };
-    fsm_template.render().unwrap()
+    Ok(fsm_template.render().unwrap())
 }
 
-pub fn create_output(out: &PathBuf, main_content: &String) {
+/// Name of the function stub generated for the choice guard, unique among the already generated ones.
+fn guard_function(guard: &str, guards: &[(String, String)]) -> String {
+    let name: String = guard
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let name = format!("guard_{}", name.trim_matches('_'));
+    let taken = |name: &String| guards.iter().any(|(function, _)| function == name);
+    if !taken(&name) {
+        return name;
+    }
+    (1..)
+        .map(|index| format!("{name}_{index}"))
+        .find(|name| !taken(name))
+        .unwrap()
+}
+
+pub fn create_output(out: &Path, main_content: &str) {
     // ignore result as some parts of the output path can be already created
     let out_src_path = out.join("src");
     let _ = std::fs::create_dir_all(&out_src_path);
     let mut file_main_rs = std::fs::File::create(out_src_path.join("main.rs")).unwrap();
-    if let Err(err) = file_main_rs.write(&main_content.as_bytes()) {
+    if let Err(err) = file_main_rs.write(main_content.as_bytes()) {
         println!("Unable to write content to main.rs, error: {err:?}");
     }
 
@@ -40,3 +104,82 @@ pub fn create_output(out: &PathBuf, main_content: &String) {
         println!("Unable to write content to Cargo.toml, error: {err:?}");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::BufRead;
+    use std::io::BufReader;
+
+    fn generate(diagram: &str) -> Result<String, String> {
+        let mut uml = Uml::default();
+        uml.parse(BufReader::new(diagram.as_bytes()).lines())?;
+        get_main(&uml)
+    }
+
+    #[test]
+    fn given_simple_diagram_when_generated_then_transitions_are_declared() {
+        // when
+        let main = generate(include_str!("../examples/simple.plantuml")).unwrap();
+
+        // then
+        assert!(main.contains("    #[default]\n    Idle,\n"));
+        assert!(main.contains("stm.on(State::Idle, Event::EvOnTouch, State::Operation);"));
+        assert!(main.contains("stm.on(State::Idle, Event::EvOnExit, State::Final);"));
+        assert!(main.contains("stm.set_final(State::Final);"));
+    }
+
+    #[test]
+    fn given_advanced_diagram_when_generated_then_composite_states_are_declared() {
+        // when
+        let main = generate(include_str!("../examples/advanced.plantuml")).unwrap();
+
+        // then
+        assert!(main.contains("stm.set_parent(State::Configuring, State::NotShooting);"));
+        assert!(main
+            .contains("stm.set_initial_substate(State::Configuring, State::NewValueSelection);"));
+        assert!(main.contains("    #[default]\n    NotShooting,\n"));
+    }
+
+    #[test]
+    fn given_choice_diagram_when_generated_then_guard_stubs_are_numbered_by_sorted_choices() {
+        // when
+        let main = generate(include_str!("../examples/choice.plantuml")).unwrap();
+
+        // then
+        assert!(main.contains(
+            "    stm.add_choice(State::Check)\n\
+            \x20       .when(guard_is_valid, State::Validate)\n\
+            \x20       .otherwise(State::Rejected);\n\
+            \x20   stm.add_choice(State::Validate)\n\
+            \x20       .when(guard_is_valid_1, State::Accepted)\n\
+            \x20       .otherwise(State::Rejected);\n"
+        ));
+        assert!(main.contains("fn guard_is_valid_1(_data: &Data<Event, State, UserData>) -> bool"));
+        assert!(main.contains("stm.on_completion(State::Accepted, State::Final);"));
+    }
+
+    #[test]
+    fn given_same_diagram_when_generated_again_then_output_is_the_same() {
+        for diagram in [
+            include_str!("../examples/simple.plantuml"),
+            include_str!("../examples/advanced.plantuml"),
+            include_str!("../examples/choice.plantuml"),
+        ] {
+            // when
+            let outputs: Vec<String> = (0..8).map(|_| generate(diagram).unwrap()).collect();
+
+            // then
+            assert!(outputs.iter().all(|output| *output == outputs[0]));
+        }
+    }
+
+    #[test]
+    fn given_diagram_without_initial_state_when_generated_then_error_is_returned() {
+        // when
+        let error = generate("Idle --> Operation : EvOnTouch\n").unwrap_err();
+
+        // then
+        assert!(error.starts_with("No initial state"));
+    }
+}
@@ -42,9 +42,18 @@ fn main() {
     println!("Generating async_fsm from: {:?}", input_path);
 
     let mut parser = parser::Uml::default();
-    parser.parse(reader);
+    if let Err(err) = parser.parse(reader) {
+        println!("Unable to parse the diagram: {input_path:?}! Error: {err}");
+        return;
+    }
 
-    let fsm_main = generator::get_main(&parser.events, &parser.states, &parser.transitions);
-    generator::create_output(&output_path, &fsm_main);
+    let fsm_main = match generator::get_main(&parser) {
+        Err(err) => {
+            println!("Unable to generate async_fsm from: {input_path:?}! Error: {err}");
+            return;
+        }
+        Ok(fsm_main) => fsm_main,
+    };
+    generator::create_output(output_path, &fsm_main);
     println!("Output generated at: {output_path:?}");
 }
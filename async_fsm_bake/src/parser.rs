@@ -5,49 +5,153 @@ use std::io::BufReader;
 use std::io::Lines;
 use std::io::Read;
 
+/// The name of the state generated for the final pseudo-state `[*]`,
+/// suffixed with the number when the diagram already has such a state.
+const FINAL_STATE: &str = "Final";
+
 #[derive(Default)]
 pub struct Uml {
     pub states: HashSet<String>,
     pub events: HashSet<String>,
     // source state, Vec<(event, dest state);
     pub transitions: HashMap<String, Vec<(String, String)>>,
+    // substate, composite state
+    pub parents: HashMap<String, String>,
+    // composite state, initial substate
+    pub initial_substates: HashMap<String, String>,
+    // choice pseudo-state, Vec<(guard, dest state)>
+    pub choices: HashMap<String, Vec<(String, String)>>,
+    // the state entered first, `[*] --> State` outside of the composite states
+    pub initial: Option<String>,
+    // final states, reached by `State --> [*]` outside of the composite states
+    pub finals: HashSet<String>,
+    // source state, Vec<dest state> of the transitions without the event
+    pub completions: HashMap<String, Vec<String>>,
+    // source state, event of the transitions into the final pseudo-state, the event is None for the completion
+    final_transitions: Vec<(String, Option<String>)>,
+    // composite states opened by `state Name {`, the innermost is the last one
+    composites: Vec<String>,
 }
 
 impl Uml {
-    pub fn parse<R: Read>(&mut self, mut lines: Lines<BufReader<R>>) {
+    /// Parse the diagram, the error names the line with the transition which can't be generated.
+    pub fn parse<R: Read>(&mut self, mut lines: Lines<BufReader<R>>) -> Result<(), String> {
+        let mut number = 0;
         while let Some(Ok(line)) = lines.next() {
-            self.parse_line(&line);
+            number += 1;
+            self.parse_line(&line)
+                .map_err(|err| format!("line {number}: {err}: {}", line.trim()))?;
         }
+        self.add_final_transitions();
+        Ok(())
     }
 
-    fn add_transition(&mut self, from: &String, event: &String, to: &String) {
-        if let Some(transition) = self.transitions.get_mut(from) {
-            transition.push((event.clone(), to.clone()));
-        } else {
-            let mut v = Vec::<(String, String)>::new();
-            v.push((event.clone(), to.clone()));
-            self.transitions.insert(from.clone(), v);
+    /// Add the final state once all the states are known, so its name doesn't collide with any of them.
+    fn add_final_transitions(&mut self) {
+        if self.final_transitions.is_empty() {
+            return;
+        }
+        let final_state = std::iter::once(FINAL_STATE.to_string())
+            .chain((1..).map(|index| format!("{FINAL_STATE}{index}")))
+            .find(|name| !self.states.contains(name))
+            .unwrap();
+        self.add_state(&final_state);
+        self.finals.insert(final_state.clone());
+        for (from, event) in std::mem::take(&mut self.final_transitions) {
+            match event {
+                Some(event) => {
+                    self.events.insert(event.clone());
+                    self.add_transition(&from, &event, &final_state);
+                }
+                None => self
+                    .completions
+                    .entry(from)
+                    .or_default()
+                    .push(final_state.clone()),
+            }
         }
     }
-    fn add_state(&mut self, state: &String) {
+
+    fn add_transition(&mut self, from: &str, event: &str, to: &str) {
+        self.transitions
+            .entry(from.to_string())
+            .or_default()
+            .push((event.to_string(), to.to_string()));
+    }
+    fn add_state(&mut self, state: &str) {
         if !self.states.contains(state) {
-            self.states.insert(state.clone());
+            self.states.insert(state.to_string());
+            if let Some(composite) = self.composites.last() {
+                self.parents.insert(state.to_string(), composite.clone());
+            }
         }
     }
 
-    fn parse_line(&mut self, line: &String) {
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let composite_begin_regex = Regex::new(r"^\s*state\s+(?<composite>\S+)\s*\{").unwrap();
+        if let Some(caps) = composite_begin_regex.captures(line) {
+            let composite = &caps["composite"];
+            self.add_state(composite);
+            self.composites.push(composite.to_string());
+            return Ok(());
+        }
+
+        let choice_regex = Regex::new(r"^\s*state\s+(?<choice>\S+)\s*<<choice>>").unwrap();
+        if let Some(caps) = choice_regex.captures(line) {
+            let choice = &caps["choice"];
+            self.add_state(choice);
+            self.choices.entry(choice.to_string()).or_default();
+            return Ok(());
+        }
+
+        let composite_end_regex = Regex::new(r"^\s*\}").unwrap();
+        if composite_end_regex.is_match(line) {
+            self.composites.pop();
+            return Ok(());
+        }
+
         let start_point_regex = Regex::new(r"\[\*\]\s*-+>\s*(?<start_point>\S+)").unwrap();
         if let Some(caps) = start_point_regex.captures(line) {
             let start_point = &caps["start_point"];
-            self.add_state(&start_point.to_string());
-            return;
+            self.add_state(start_point);
+            match self.composites.last() {
+                Some(composite) => {
+                    self.initial_substates
+                        .insert(composite.clone(), start_point.to_string());
+                }
+                None => self.initial = Some(start_point.to_string()),
+            }
+            return Ok(());
         }
 
-        let end_point_regex = Regex::new(r"\s*(?<end_point>\S+)\s*-+>\s*\[\*\]").unwrap();
+        let end_point_regex =
+            Regex::new(r"\s*(?<end_point>\S+)\s*-+>\s*\[\*\](\s*:\s*(?<event>\S+))?").unwrap();
         if let Some(caps) = end_point_regex.captures(line) {
             let end_point = &caps["end_point"];
-            self.add_state(&end_point.to_string());
-            return;
+            if let Some(composite) = self.composites.last() {
+                return Err(format!(
+                    "the final state inside the composite state {composite} is not supported"
+                ));
+            }
+            self.add_state(end_point);
+            let event = caps.name("event").map(|event| event.as_str().to_string());
+            self.final_transitions.push((end_point.to_string(), event));
+            return Ok(());
+        }
+
+        let branch_regex =
+            Regex::new(r"(?<from>\S+)\s*-+>\s*(?<to>\S+)\s*:\s*\[(?<guard>[^\]]*)\]").unwrap();
+        if let Some(caps) = branch_regex.captures(line) {
+            let from = &caps["from"];
+            let to = &caps["to"];
+            let Some(branches) = self.choices.get_mut(from) else {
+                return Err(format!(
+                    "the guarded transition from {from} is supported only for the choice declared before with `state {from} <<choice>>`"
+                ));
+            };
+            branches.push((caps["guard"].trim().to_string(), to.to_string()));
+            self.add_state(to);
+            return Ok(());
         }
 
         let transition_regex =
@@ -56,11 +160,157 @@ impl Uml {
             let from = &caps["from"];
             let to = &caps["to"];
             let event = &caps["event"];
-            self.add_state(&from.to_string());
-            self.add_state(&to.to_string());
+            self.add_state(from);
+            self.add_state(to);
             self.events.insert(event.to_string());
-            self.add_transition(&from.into(), &event.into(), &to.into());
-            return;
+            self.add_transition(from, event, to);
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::BufRead;
+
+    fn parse(diagram: &str) -> Uml {
+        let mut uml = Uml::default();
+        uml.parse(BufReader::new(diagram.as_bytes()).lines())
+            .unwrap();
+        uml
+    }
+
+    fn set(items: &[&str]) -> HashSet<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    fn map(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(first, second)| (first.to_string(), second.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn given_simple_diagram_when_parsed_then_final_transition_leads_to_final_state() {
+        // when
+        let uml = parse(include_str!("../examples/simple.plantuml"));
+
+        // then
+        assert_eq!(uml.initial.as_deref(), Some("Idle"));
+        assert_eq!(uml.states, set(&["Idle", "Operation", "Final"]));
+        assert_eq!(uml.events, set(&["EvOnExit", "EvOnTouch", "EvOnKeyDown"]));
+        assert_eq!(
+            uml.transitions["Idle"],
+            pairs(&[("EvOnTouch", "Operation"), ("EvOnExit", "Final")])
+        );
+        assert_eq!(
+            uml.transitions["Operation"],
+            pairs(&[("EvOnKeyDown", "Idle")])
+        );
+        assert_eq!(uml.finals, set(&["Final"]));
+        assert!(uml.parents.is_empty());
+    }
+
+    #[test]
+    fn given_advanced_diagram_when_parsed_then_composite_states_are_nested() {
+        // when
+        let uml = parse(include_str!("../examples/advanced.plantuml"));
+
+        // then
+        assert_eq!(uml.initial.as_deref(), Some("NotShooting"));
+        assert_eq!(
+            uml.parents,
+            map(&[
+                ("Idle", "NotShooting"),
+                ("Configuring", "NotShooting"),
+                ("NewValueSelection", "Configuring"),
+                ("NewValuePreview", "Configuring"),
+            ])
+        );
+        assert_eq!(
+            uml.initial_substates,
+            map(&[
+                ("NotShooting", "Idle"),
+                ("Configuring", "NewValueSelection")
+            ])
+        );
+        assert_eq!(
+            uml.transitions["NewValuePreview"],
+            pairs(&[
+                ("EvNewValueRejected", "NewValueSelection"),
+                ("EvNewValueSaved", "NewValueSelection")
+            ])
+        );
+        // The transitions without the event are not parsed inside the composite states.
+        assert!(!uml.states.contains("State1"));
+        assert!(uml.finals.is_empty());
+    }
+
+    #[test]
+    fn given_choice_diagram_when_parsed_then_branches_keep_the_declaration_order() {
+        // when
+        let uml = parse(include_str!("../examples/choice.plantuml"));
+
+        // then
+        assert_eq!(
+            uml.choices["Check"],
+            pairs(&[("is valid", "Validate"), ("else", "Rejected")])
+        );
+        assert_eq!(
+            uml.choices["Validate"],
+            pairs(&[("is valid", "Accepted"), ("else", "Rejected")])
+        );
+        assert_eq!(uml.transitions["Idle"], pairs(&[("EvSubmit", "Check")]));
+        assert_eq!(uml.completions["Accepted"], vec!["Final".to_string()]);
+        assert_eq!(uml.finals, set(&["Final"]));
+    }
+
+    #[test]
+    fn given_state_named_final_when_parsed_then_final_state_is_numbered() {
+        // when
+        let uml = parse("[*] --> Final\nFinal --> [*] : EvStop\n");
+
+        // then
+        assert_eq!(uml.finals, set(&["Final1"]));
+        assert_eq!(uml.transitions["Final"], pairs(&[("EvStop", "Final1")]));
+    }
+
+    #[test]
+    fn given_final_transition_inside_composite_state_when_parsed_then_error_names_the_line() {
+        // given
+        let diagram =
+            "[*] --> Active\nstate Active {\n  [*] --> Busy\n  Busy --> [*] : EvDone\n}\n";
+
+        // when
+        let result = Uml::default().parse(BufReader::new(diagram.as_bytes()).lines());
+
+        // then
+        assert_eq!(
+            result,
+            Err("line 4: the final state inside the composite state Active is not supported: Busy --> [*] : EvDone".to_string())
+        );
+    }
+
+    #[test]
+    fn given_guarded_transition_from_plain_state_when_parsed_then_error_names_the_line() {
+        // given
+        let diagram = "[*] --> Idle\nIdle --> Busy : [is ready]\n";
+
+        // when
+        let result = Uml::default().parse(BufReader::new(diagram.as_bytes()).lines());
+
+        // then
+        assert!(result
+            .unwrap_err()
+            .starts_with("line 2: the guarded transition from Idle"));
     }
 }
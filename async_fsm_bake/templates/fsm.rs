@@ -24,7 +24,7 @@ struct BatteryLevelState;
 
 #[async_trait]
 impl Transition<Event, State, UserData> for BatteryLevelState {
-    async fn next(&mut self, event: Event, data: &Data<Event, State, UserData>) -> State {
+    async fn next(&mut self, event: Event, data: &mut Data<Event, State, UserData>) -> State {
         match event {
             _ => data.state,
         }
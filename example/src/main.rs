@@ -1,7 +1,7 @@
 use async_fsm::*;
 use async_trait::async_trait;
-use log::info;
 use log::LevelFilter;
+use log::{error, info};
 use std::io::Write;
 
 #[derive(Default, Debug, Eq, PartialEq, Copy, Clone, Hash)]
@@ -29,7 +29,7 @@ struct BatteryLevelState;
 
 #[async_trait]
 impl Transition<Event, State, UserData> for BatteryLevelState {
-    async fn next(&mut self, event: Event, data: &Data<Event, State, UserData>) -> State {
+    async fn next(&mut self, event: Event, data: &mut Data<Event, State, UserData>) -> State {
         match event {
             Event::BatteryLevel(level) if level >= 80 => State::FullyCharged,
             Event::BatteryLevel(level) if level >= 50 => State::HalflyCharged,
@@ -43,7 +43,7 @@ impl Transition<Event, State, UserData> for BatteryLevelState {
 struct ChargingState;
 #[async_trait]
 impl Transition<Event, State, UserData> for ChargingState {
-    async fn next(&mut self, event: Event, data: &Data<Event, State, UserData>) -> State {
+    async fn next(&mut self, event: Event, data: &mut Data<Event, State, UserData>) -> State {
         match event {
             Event::PlugOut => State::Unknown, // switch to unknown state and wait for battery level update.
             _ => data.state,
@@ -66,15 +66,14 @@ async fn main() {
     stm.add_transition(State::FullyCharged, battery_level_state.clone());
     stm.add_transition(State::Charging, Box::new(ChargingState {}));
 
-    let mut state_subscription = stm.subscribe();
+    let shutdown = stm.shutdown_handle();
+    let mut records = stm.subscribe_transitions();
 
-    let _ = tokio::spawn(async move {
-        stm.process().await;
-    });
+    let fsm = tokio::spawn(async move { stm.process().await });
 
     let states = tokio::spawn(async move {
-        while let Ok(state) = state_subscription.recv().await {
-            info!("Changed State: {state:?}");
+        while let Ok(record) = records.recv().await {
+            info!("Changed State: {:?}", record.state);
         }
     });
 
@@ -84,5 +83,15 @@ async fn main() {
     let _ = event_sender.send(Event::PlugOut).await;
     let _ = event_sender.send(Event::BatteryLevel(55)).await;
 
+    // Process the events sent so far and stop the machine.
+    shutdown.shutdown(Shutdown::Drain);
+    match fsm.await.unwrap() {
+        Ok(termination) => info!(
+            "Terminated: {:?}, user data: {:?}",
+            termination.reason, termination.data.user_data
+        ),
+        Err(failure) => error!("Failed: {failure}"),
+    }
+
     let _ = states.await;
 }